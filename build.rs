@@ -1,4 +1,11 @@
 fn main() {
-    tonic_build::compile_protos("proto/axidraw_over_http.proto")
+    tonic_build::configure()
+        .compile(
+            &[
+                "proto/axidraw_over_http.proto",
+                "local_proto/axidraw_over_tcp.proto",
+            ],
+            &["proto", "local_proto"],
+        )
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
@@ -0,0 +1,94 @@
+use crate::{
+    ebb::{EbbCommand, PenState},
+    kinematics,
+    limits::TravelLimits,
+    position::{Point, Position, Scale},
+};
+use std::time::Duration;
+
+/// A line of a job that is not a valid EBB command, or a move beyond the travel limits.
+pub struct InvalidCommand {
+    /// Position of the line in the job, from 0.
+    pub index: usize,
+    pub command: String,
+    pub error: String,
+}
+
+/// What a job would do, worked out by running it through a model of the plotter rather than the
+/// EBB itself.
+pub struct DryRun {
+    pub duration: Duration,
+    /// Distance travelled with the pen down, in steps along the X and Y axes.
+    pub pen_down_distance: f64,
+    pub pen_down_distance_mm: f64,
+    /// Corners of the area the carriage moves over, with the pen up or down, in steps.
+    pub min: Point,
+    pub max: Point,
+    /// The same corners in millimetres.
+    pub min_mm: Point,
+    pub max_mm: Point,
+    /// Times the pen is raised after drawing.
+    pub pen_lifts: u64,
+    pub invalid_commands: Vec<InvalidCommand>,
+}
+
+impl DryRun {
+    /// Runs `commands` from home with the pen up. Invalid commands and moves beyond `limits` are
+    /// noted and skipped, so the rest of the job can still be checked.
+    pub fn run(commands: &[String], scale: &Scale, limits: Option<&TravelLimits>) -> DryRun {
+        let mut position = Position::default();
+        let home = Point::from(position);
+        let mut dry_run = DryRun {
+            duration: Duration::ZERO,
+            pen_down_distance: 0.0,
+            pen_down_distance_mm: 0.0,
+            min: home,
+            max: home,
+            min_mm: home,
+            max_mm: home,
+            pen_lifts: 0,
+            invalid_commands: Vec::new(),
+        };
+
+        for (index, contents) in commands.iter().enumerate() {
+            let checked = contents
+                .parse::<EbbCommand>()
+                .map_err(|e| e.to_string())
+                .and_then(|command| match limits {
+                    Some(limits) => limits.check(scale, position, &command).map(|()| command),
+                    None => Ok(command),
+                });
+            let command = match checked {
+                Ok(command) => command,
+                Err(error) => {
+                    dry_run.invalid_commands.push(InvalidCommand {
+                        index,
+                        command: contents.clone(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+
+            let next = position.after(&command);
+            let (from, to) = (Point::from(position), Point::from(next));
+            let (from_mm, to_mm) = (scale.in_mm(position), scale.in_mm(next));
+
+            dry_run.duration += kinematics::duration(position, &command);
+            if position.pen == PenState::Down {
+                dry_run.pen_down_distance += from.distance_to(to);
+                dry_run.pen_down_distance_mm += from_mm.distance_to(to_mm);
+                if next.pen == PenState::Up {
+                    dry_run.pen_lifts += 1;
+                }
+            }
+            (dry_run.min, dry_run.max) = (dry_run.min.min(to), dry_run.max.max(to));
+            (dry_run.min_mm, dry_run.max_mm) =
+                (dry_run.min_mm.min(to_mm), dry_run.max_mm.max(to_mm));
+
+            position = next;
+        }
+
+        dry_run
+    }
+}
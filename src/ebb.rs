@@ -0,0 +1,1547 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// Largest step count or duration accepted by the motion commands.
+const MAX_MOVE_VALUE: i64 = 16_777_215;
+
+/// Fastest step rate, in steps per millisecond, the EBB can produce on either axis.
+pub const MAX_STEPS_PER_MS: i64 = 25;
+
+/// Oldest firmware this server drives. `HM` arrived in 2.6.2, but only took a position to move
+/// to, used to recover, return to the user origin and draw SVGs, from 3.0.0.
+pub const MINIMUM_FIRMWARE_VERSION: (u32, u32, u32) = (3, 0, 0);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PenState {
+    #[default]
+    Up,
+    Down,
+}
+
+/// A single EiBotBoard command, validated against the firmware's documented argument ranges.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EbbCommand {
+    /// `AC` - configure an analog input channel.
+    AnalogConfigure { channel: u8, enable: bool },
+    /// `BL` - enter the bootloader.
+    Bootloader,
+    /// `CK` - echo a fixed set of test values back.
+    Check,
+    /// `CS` - clear the global step position.
+    ClearStepPosition,
+    /// `CU` - configure a user option.
+    ConfigureUser { parameter: u8, value: i16 },
+    /// `EM` - enable or disable the motors and set microstepping.
+    EnableMotors { enable1: u8, enable2: Option<u8> },
+    /// `ES` - emergency stop.
+    EmergencyStop { disable_motors: Option<bool> },
+    /// `HM` - move home, or to an absolute position.
+    HomeMove {
+        step_frequency: u16,
+        position: Option<(i32, i32)>,
+    },
+    /// `LM` - low-level move with acceleration.
+    LowLevelMove {
+        rate1: u32,
+        steps1: i32,
+        accel1: i32,
+        rate2: u32,
+        steps2: i32,
+        accel2: i32,
+        clear: Option<u8>,
+    },
+    /// `LT` - low-level move, time limited.
+    LowLevelMoveTimed {
+        intervals: u32,
+        rate1: i32,
+        accel1: i32,
+        rate2: i32,
+        accel2: i32,
+        clear: Option<u8>,
+    },
+    /// `ND` - decrement the node count.
+    NodeCountDecrement,
+    /// `NI` - increment the node count.
+    NodeCountIncrement,
+    /// `QB` - query whether the PRG button has been pressed.
+    QueryButton,
+    /// `QC` - query the current and voltage sense values.
+    QueryCurrent,
+    /// `QE` - query motor enables and microstep resolutions.
+    QueryEnables,
+    /// `QG` - query general status.
+    QueryGeneral,
+    /// `QL` - query the layer variable.
+    QueryLayer,
+    /// `QM` - query motor status.
+    QueryMotors,
+    /// `QN` - query the node count.
+    QueryNodeCount,
+    /// `QP` - query the pen state.
+    QueryPen,
+    /// `QR` - query RC servo power state.
+    QueryServoPower,
+    /// `QS` - query the global step position.
+    QueryStepPosition,
+    /// `QT` - query the EBB nickname.
+    QueryNickname,
+    /// `R` - reset to default state.
+    Reset,
+    /// `RB` - reboot.
+    Reboot,
+    /// `S2` - general RC servo output.
+    ServoOutput {
+        position: u16,
+        output_pin: u8,
+        rate: Option<u16>,
+        delay: Option<u16>,
+    },
+    /// `SC` - configure stepper and servo modes.
+    StepperServoConfigure { parameter: u8, value: u16 },
+    /// `SE` - set the engraver output.
+    SetEngraver {
+        enable: bool,
+        power: Option<u16>,
+        use_motion_queue: Option<bool>,
+    },
+    /// `SL` - set the layer variable.
+    SetLayer { layer: u8 },
+    /// `SM` - stepper move.
+    StepperMove {
+        duration: u32,
+        steps1: i32,
+        steps2: Option<i32>,
+    },
+    /// `SN` - set the node count.
+    SetNodeCount { count: u32 },
+    /// `SP` - raise or lower the pen.
+    SetPen {
+        state: PenState,
+        duration: Option<u16>,
+        port_b_pin: Option<u8>,
+    },
+    /// `SR` - set the RC servo power timeout.
+    SetServoPower { timeout: u32, state: Option<bool> },
+    /// `ST` - set the EBB nickname.
+    SetNickname { nickname: String },
+    /// `TP` - toggle the pen.
+    TogglePen { duration: Option<u16> },
+    /// `V` - query the firmware version.
+    Version,
+    /// `XM` - mixed-axis stepper move.
+    MixedAxisMove {
+        duration: u32,
+        steps_a: i32,
+        steps_b: i32,
+    },
+}
+
+impl EbbCommand {
+    /// The command's mnemonic as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EbbCommand::AnalogConfigure { .. } => "AC",
+            EbbCommand::Bootloader => "BL",
+            EbbCommand::Check => "CK",
+            EbbCommand::ClearStepPosition => "CS",
+            EbbCommand::ConfigureUser { .. } => "CU",
+            EbbCommand::EnableMotors { .. } => "EM",
+            EbbCommand::EmergencyStop { .. } => "ES",
+            EbbCommand::HomeMove { .. } => "HM",
+            EbbCommand::LowLevelMove { .. } => "LM",
+            EbbCommand::LowLevelMoveTimed { .. } => "LT",
+            EbbCommand::NodeCountDecrement => "ND",
+            EbbCommand::NodeCountIncrement => "NI",
+            EbbCommand::QueryButton => "QB",
+            EbbCommand::QueryCurrent => "QC",
+            EbbCommand::QueryEnables => "QE",
+            EbbCommand::QueryGeneral => "QG",
+            EbbCommand::QueryLayer => "QL",
+            EbbCommand::QueryMotors => "QM",
+            EbbCommand::QueryNodeCount => "QN",
+            EbbCommand::QueryPen => "QP",
+            EbbCommand::QueryServoPower => "QR",
+            EbbCommand::QueryStepPosition => "QS",
+            EbbCommand::QueryNickname => "QT",
+            EbbCommand::Reset => "R",
+            EbbCommand::Reboot => "RB",
+            EbbCommand::ServoOutput { .. } => "S2",
+            EbbCommand::StepperServoConfigure { .. } => "SC",
+            EbbCommand::SetEngraver { .. } => "SE",
+            EbbCommand::SetLayer { .. } => "SL",
+            EbbCommand::StepperMove { .. } => "SM",
+            EbbCommand::SetNodeCount { .. } => "SN",
+            EbbCommand::SetPen { .. } => "SP",
+            EbbCommand::SetServoPower { .. } => "SR",
+            EbbCommand::SetNickname { .. } => "ST",
+            EbbCommand::TogglePen { .. } => "TP",
+            EbbCommand::Version => "V",
+            EbbCommand::MixedAxisMove { .. } => "XM",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseCommandError {
+    Empty,
+    InvalidCharacter(char),
+    UnknownCommand(String),
+    WrongArgumentCount {
+        command: &'static str,
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+    InvalidArgument {
+        command: &'static str,
+        argument: &'static str,
+        value: String,
+    },
+    OutOfRange {
+        command: &'static str,
+        argument: &'static str,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    StepRateTooHigh {
+        command: &'static str,
+        steps: i64,
+        duration: i64,
+    },
+}
+
+impl Display for ParseCommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseCommandError::Empty => write!(f, "command is empty"),
+            ParseCommandError::InvalidCharacter(c) => {
+                write!(f, "command contains invalid character {:?}", c)
+            }
+            ParseCommandError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            ParseCommandError::WrongArgumentCount {
+                command,
+                min,
+                max,
+                found,
+            } => {
+                if min == max {
+                    write!(f, "{} takes {} argument(s), got {}", command, min, found)
+                } else {
+                    write!(
+                        f,
+                        "{} takes {} to {} arguments, got {}",
+                        command, min, max, found
+                    )
+                }
+            }
+            ParseCommandError::InvalidArgument {
+                command,
+                argument,
+                value,
+            } => write!(
+                f,
+                "{}: {} must be an integer, got '{}'",
+                command, argument, value
+            ),
+            ParseCommandError::OutOfRange {
+                command,
+                argument,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "{}: {} must be between {} and {}, got {}",
+                command, argument, min, max, value
+            ),
+            ParseCommandError::StepRateTooHigh {
+                command,
+                steps,
+                duration,
+            } => write!(
+                f,
+                "{}: {} steps in {} ms exceeds the maximum rate of {} steps/ms",
+                command, steps, duration, MAX_STEPS_PER_MS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseCommandError {}
+
+/// Positional arguments of a command being parsed, with range-checked accessors.
+struct Arguments<'a> {
+    command: &'static str,
+    values: Vec<&'a str>,
+}
+
+impl<'a> Arguments<'a> {
+    fn expect_count(&self, min: usize, max: usize) -> Result<(), ParseCommandError> {
+        if self.values.len() < min || self.values.len() > max {
+            return Err(ParseCommandError::WrongArgumentCount {
+                command: self.command,
+                min,
+                max,
+                found: self.values.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn get(
+        &self,
+        index: usize,
+        argument: &'static str,
+        min: i64,
+        max: i64,
+    ) -> Result<i64, ParseCommandError> {
+        let raw = self.values[index].trim();
+        let value = raw
+            .parse::<i64>()
+            .map_err(|_| ParseCommandError::InvalidArgument {
+                command: self.command,
+                argument,
+                value: raw.to_string(),
+            })?;
+
+        if value < min || value > max {
+            return Err(ParseCommandError::OutOfRange {
+                command: self.command,
+                argument,
+                value,
+                min,
+                max,
+            });
+        }
+
+        Ok(value)
+    }
+
+    fn get_optional(
+        &self,
+        index: usize,
+        argument: &'static str,
+        min: i64,
+        max: i64,
+    ) -> Result<Option<i64>, ParseCommandError> {
+        if index < self.values.len() {
+            self.get(index, argument, min, max).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_step_rate(&self, steps: i64, duration: i64) -> Result<(), ParseCommandError> {
+        if steps.abs() > duration * MAX_STEPS_PER_MS {
+            return Err(ParseCommandError::StepRateTooHigh {
+                command: self.command,
+                steps,
+                duration,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for EbbCommand {
+    type Err = ParseCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(c) = s.chars().find(|c| c.is_control()) {
+            return Err(ParseCommandError::InvalidCharacter(c));
+        }
+
+        let mut parts = s.trim().split(',');
+        let name = parts.next().unwrap_or_default().trim().to_ascii_uppercase();
+        if name.is_empty() {
+            return Err(ParseCommandError::Empty);
+        }
+
+        let mut values: Vec<&str> = parts.collect();
+        // The EBB tolerates a trailing comma, e.g. "QS,".
+        if values.last().is_some_and(|v| v.trim().is_empty()) {
+            values.pop();
+        }
+
+        let command = match KNOWN_COMMANDS.iter().find(|known| **known == name) {
+            Some(known) => *known,
+            None => return Err(ParseCommandError::UnknownCommand(name)),
+        };
+        let args = Arguments { command, values };
+        let flag = |value: i64| value != 0;
+
+        let parsed = match command {
+            "AC" => {
+                args.expect_count(2, 2)?;
+                EbbCommand::AnalogConfigure {
+                    channel: args.get(0, "channel", 0, 15)? as u8,
+                    enable: flag(args.get(1, "enable", 0, 1)?),
+                }
+            }
+            "BL" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::Bootloader
+            }
+            "CK" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::Check
+            }
+            "CS" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::ClearStepPosition
+            }
+            "CU" => {
+                args.expect_count(2, 2)?;
+                EbbCommand::ConfigureUser {
+                    parameter: args.get(0, "parameter", 0, 255)? as u8,
+                    value: args.get(1, "value", i16::MIN.into(), i16::MAX.into())? as i16,
+                }
+            }
+            "EM" => {
+                args.expect_count(1, 2)?;
+                EbbCommand::EnableMotors {
+                    enable1: args.get(0, "enable1", 0, 5)? as u8,
+                    enable2: args.get_optional(1, "enable2", 0, 1)?.map(|v| v as u8),
+                }
+            }
+            "ES" => {
+                args.expect_count(0, 1)?;
+                EbbCommand::EmergencyStop {
+                    disable_motors: args.get_optional(0, "disable_motors", 0, 1)?.map(flag),
+                }
+            }
+            "HM" => {
+                args.expect_count(1, 3)?;
+                if args.values.len() == 2 {
+                    return Err(ParseCommandError::WrongArgumentCount {
+                        command,
+                        min: 3,
+                        max: 3,
+                        found: 2,
+                    });
+                }
+                let position = match args.get_optional(1, "position1", -4_294_967, 4_294_967)? {
+                    Some(position1) => Some((
+                        position1 as i32,
+                        args.get(2, "position2", -4_294_967, 4_294_967)? as i32,
+                    )),
+                    None => None,
+                };
+                EbbCommand::HomeMove {
+                    step_frequency: args.get(0, "step_frequency", 2, 25_000)? as u16,
+                    position,
+                }
+            }
+            "LM" => {
+                args.expect_count(6, 7)?;
+                EbbCommand::LowLevelMove {
+                    rate1: args.get(0, "rate1", 0, i32::MAX.into())? as u32,
+                    steps1: args.get(1, "steps1", i32::MIN.into(), i32::MAX.into())? as i32,
+                    accel1: args.get(2, "accel1", i32::MIN.into(), i32::MAX.into())? as i32,
+                    rate2: args.get(3, "rate2", 0, i32::MAX.into())? as u32,
+                    steps2: args.get(4, "steps2", i32::MIN.into(), i32::MAX.into())? as i32,
+                    accel2: args.get(5, "accel2", i32::MIN.into(), i32::MAX.into())? as i32,
+                    clear: args.get_optional(6, "clear", 0, 3)?.map(|v| v as u8),
+                }
+            }
+            "LT" => {
+                args.expect_count(5, 6)?;
+                EbbCommand::LowLevelMoveTimed {
+                    intervals: args.get(0, "intervals", 1, i32::MAX.into())? as u32,
+                    rate1: args.get(1, "rate1", i32::MIN.into(), i32::MAX.into())? as i32,
+                    accel1: args.get(2, "accel1", i32::MIN.into(), i32::MAX.into())? as i32,
+                    rate2: args.get(3, "rate2", i32::MIN.into(), i32::MAX.into())? as i32,
+                    accel2: args.get(4, "accel2", i32::MIN.into(), i32::MAX.into())? as i32,
+                    clear: args.get_optional(5, "clear", 0, 3)?.map(|v| v as u8),
+                }
+            }
+            "ND" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::NodeCountDecrement
+            }
+            "NI" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::NodeCountIncrement
+            }
+            "QB" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryButton
+            }
+            "QC" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryCurrent
+            }
+            "QE" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryEnables
+            }
+            "QG" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryGeneral
+            }
+            "QL" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryLayer
+            }
+            "QM" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryMotors
+            }
+            "QN" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryNodeCount
+            }
+            "QP" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryPen
+            }
+            "QR" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryServoPower
+            }
+            "QS" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryStepPosition
+            }
+            "QT" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::QueryNickname
+            }
+            "R" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::Reset
+            }
+            "RB" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::Reboot
+            }
+            "S2" => {
+                args.expect_count(2, 4)?;
+                EbbCommand::ServoOutput {
+                    position: args.get(0, "position", 0, 65_535)? as u16,
+                    output_pin: args.get(1, "output_pin", 0, 24)? as u8,
+                    rate: args.get_optional(2, "rate", 0, 65_535)?.map(|v| v as u16),
+                    delay: args.get_optional(3, "delay", 0, 65_535)?.map(|v| v as u16),
+                }
+            }
+            "SC" => {
+                args.expect_count(2, 2)?;
+                EbbCommand::StepperServoConfigure {
+                    parameter: args.get(0, "parameter", 0, 255)? as u8,
+                    value: args.get(1, "value", 0, 65_535)? as u16,
+                }
+            }
+            "SE" => {
+                args.expect_count(1, 3)?;
+                EbbCommand::SetEngraver {
+                    enable: flag(args.get(0, "enable", 0, 1)?),
+                    power: args.get_optional(1, "power", 0, 1_023)?.map(|v| v as u16),
+                    use_motion_queue: args.get_optional(2, "use_motion_queue", 0, 1)?.map(flag),
+                }
+            }
+            "SL" => {
+                args.expect_count(1, 1)?;
+                EbbCommand::SetLayer {
+                    layer: args.get(0, "layer", 0, 127)? as u8,
+                }
+            }
+            "SM" => {
+                args.expect_count(2, 3)?;
+                let duration = args.get(0, "duration", 1, MAX_MOVE_VALUE)?;
+                let steps1 = args.get(1, "steps1", -MAX_MOVE_VALUE, MAX_MOVE_VALUE)?;
+                let steps2 = args.get_optional(2, "steps2", -MAX_MOVE_VALUE, MAX_MOVE_VALUE)?;
+                args.check_step_rate(steps1, duration)?;
+                args.check_step_rate(steps2.unwrap_or_default(), duration)?;
+                EbbCommand::StepperMove {
+                    duration: duration as u32,
+                    steps1: steps1 as i32,
+                    steps2: steps2.map(|v| v as i32),
+                }
+            }
+            "SN" => {
+                args.expect_count(1, 1)?;
+                EbbCommand::SetNodeCount {
+                    count: args.get(0, "count", 0, u32::MAX.into())? as u32,
+                }
+            }
+            "SP" => {
+                args.expect_count(1, 3)?;
+                EbbCommand::SetPen {
+                    state: if flag(args.get(0, "value", 0, 1)?) {
+                        PenState::Up
+                    } else {
+                        PenState::Down
+                    },
+                    duration: args
+                        .get_optional(1, "duration", 0, 65_535)?
+                        .map(|v| v as u16),
+                    port_b_pin: args.get_optional(2, "port_b_pin", 0, 7)?.map(|v| v as u8),
+                }
+            }
+            "SR" => {
+                args.expect_count(1, 2)?;
+                EbbCommand::SetServoPower {
+                    timeout: args.get(0, "timeout", 0, u32::MAX.into())? as u32,
+                    state: args.get_optional(1, "state", 0, 1)?.map(flag),
+                }
+            }
+            "ST" => {
+                args.expect_count(0, 1)?;
+                let nickname = args.values.first().copied().unwrap_or_default();
+                if nickname.len() > 16 {
+                    return Err(ParseCommandError::OutOfRange {
+                        command,
+                        argument: "nickname length",
+                        value: nickname.len() as i64,
+                        min: 0,
+                        max: 16,
+                    });
+                }
+                EbbCommand::SetNickname {
+                    nickname: nickname.to_string(),
+                }
+            }
+            "TP" => {
+                args.expect_count(0, 1)?;
+                EbbCommand::TogglePen {
+                    duration: args
+                        .get_optional(0, "duration", 0, 65_535)?
+                        .map(|v| v as u16),
+                }
+            }
+            "V" => {
+                args.expect_count(0, 0)?;
+                EbbCommand::Version
+            }
+            "XM" => {
+                args.expect_count(3, 3)?;
+                let duration = args.get(0, "duration", 1, MAX_MOVE_VALUE)?;
+                let steps_a = args.get(1, "steps_a", -MAX_MOVE_VALUE, MAX_MOVE_VALUE)?;
+                let steps_b = args.get(2, "steps_b", -MAX_MOVE_VALUE, MAX_MOVE_VALUE)?;
+                // Mixed-axis moves drive each motor with the sum and difference of A and B.
+                args.check_step_rate(steps_a + steps_b, duration)?;
+                args.check_step_rate(steps_a - steps_b, duration)?;
+                EbbCommand::MixedAxisMove {
+                    duration: duration as u32,
+                    steps_a: steps_a as i32,
+                    steps_b: steps_b as i32,
+                }
+            }
+            _ => unreachable!("{} is listed in KNOWN_COMMANDS but not parsed", command),
+        };
+
+        Ok(parsed)
+    }
+}
+
+const KNOWN_COMMANDS: &[&str] = &[
+    "AC", "BL", "CK", "CS", "CU", "EM", "ES", "HM", "LM", "LT", "ND", "NI", "QB", "QC", "QE", "QG",
+    "QL", "QM", "QN", "QP", "QR", "QS", "QT", "R", "RB", "S2", "SC", "SE", "SL", "SM", "SN", "SP",
+    "SR", "ST", "TP", "V", "XM",
+];
+
+impl Display for EbbCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+
+        let mut arg = |value: &dyn Display| write!(f, ",{}", value);
+
+        match self {
+            EbbCommand::AnalogConfigure { channel, enable } => {
+                arg(channel)?;
+                arg(&u8::from(*enable))?;
+            }
+            EbbCommand::ConfigureUser { parameter, value } => {
+                arg(parameter)?;
+                arg(value)?;
+            }
+            EbbCommand::EnableMotors { enable1, enable2 } => {
+                arg(enable1)?;
+                if let Some(enable2) = enable2 {
+                    arg(enable2)?;
+                }
+            }
+            EbbCommand::EmergencyStop { disable_motors } => {
+                if let Some(disable_motors) = disable_motors {
+                    arg(&u8::from(*disable_motors))?;
+                }
+            }
+            EbbCommand::HomeMove {
+                step_frequency,
+                position,
+            } => {
+                arg(step_frequency)?;
+                if let Some((position1, position2)) = position {
+                    arg(position1)?;
+                    arg(position2)?;
+                }
+            }
+            EbbCommand::LowLevelMove {
+                rate1,
+                steps1,
+                accel1,
+                rate2,
+                steps2,
+                accel2,
+                clear,
+            } => {
+                arg(rate1)?;
+                arg(steps1)?;
+                arg(accel1)?;
+                arg(rate2)?;
+                arg(steps2)?;
+                arg(accel2)?;
+                if let Some(clear) = clear {
+                    arg(clear)?;
+                }
+            }
+            EbbCommand::LowLevelMoveTimed {
+                intervals,
+                rate1,
+                accel1,
+                rate2,
+                accel2,
+                clear,
+            } => {
+                arg(intervals)?;
+                arg(rate1)?;
+                arg(accel1)?;
+                arg(rate2)?;
+                arg(accel2)?;
+                if let Some(clear) = clear {
+                    arg(clear)?;
+                }
+            }
+            EbbCommand::ServoOutput {
+                position,
+                output_pin,
+                rate,
+                delay,
+            } => {
+                arg(position)?;
+                arg(output_pin)?;
+                if rate.is_some() || delay.is_some() {
+                    arg(&rate.unwrap_or_default())?;
+                }
+                if let Some(delay) = delay {
+                    arg(delay)?;
+                }
+            }
+            EbbCommand::StepperServoConfigure { parameter, value } => {
+                arg(parameter)?;
+                arg(value)?;
+            }
+            EbbCommand::SetEngraver {
+                enable,
+                power,
+                use_motion_queue,
+            } => {
+                arg(&u8::from(*enable))?;
+                if power.is_some() || use_motion_queue.is_some() {
+                    arg(&power.unwrap_or_default())?;
+                }
+                if let Some(use_motion_queue) = use_motion_queue {
+                    arg(&u8::from(*use_motion_queue))?;
+                }
+            }
+            EbbCommand::SetLayer { layer } => arg(layer)?,
+            EbbCommand::StepperMove {
+                duration,
+                steps1,
+                steps2,
+            } => {
+                arg(duration)?;
+                arg(steps1)?;
+                if let Some(steps2) = steps2 {
+                    arg(steps2)?;
+                }
+            }
+            EbbCommand::SetNodeCount { count } => arg(count)?,
+            EbbCommand::SetPen {
+                state,
+                duration,
+                port_b_pin,
+            } => {
+                arg(&u8::from(*state == PenState::Up))?;
+                if duration.is_some() || port_b_pin.is_some() {
+                    arg(&duration.unwrap_or_default())?;
+                }
+                if let Some(port_b_pin) = port_b_pin {
+                    arg(port_b_pin)?;
+                }
+            }
+            EbbCommand::SetServoPower { timeout, state } => {
+                arg(timeout)?;
+                if let Some(state) = state {
+                    arg(&u8::from(*state))?;
+                }
+            }
+            EbbCommand::SetNickname { nickname } => arg(nickname)?,
+            EbbCommand::TogglePen { duration } => {
+                if let Some(duration) = duration {
+                    arg(duration)?;
+                }
+            }
+            EbbCommand::MixedAxisMove {
+                duration,
+                steps_a,
+                steps_b,
+            } => {
+                arg(duration)?;
+                arg(steps_a)?;
+                arg(steps_b)?;
+            }
+            EbbCommand::Bootloader
+            | EbbCommand::Check
+            | EbbCommand::ClearStepPosition
+            | EbbCommand::NodeCountDecrement
+            | EbbCommand::NodeCountIncrement
+            | EbbCommand::QueryButton
+            | EbbCommand::QueryCurrent
+            | EbbCommand::QueryEnables
+            | EbbCommand::QueryGeneral
+            | EbbCommand::QueryLayer
+            | EbbCommand::QueryMotors
+            | EbbCommand::QueryNodeCount
+            | EbbCommand::QueryPen
+            | EbbCommand::QueryServoPower
+            | EbbCommand::QueryStepPosition
+            | EbbCommand::QueryNickname
+            | EbbCommand::Reset
+            | EbbCommand::Reboot
+            | EbbCommand::Version => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// How many lines the EBB sends back for a command, and in what order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseShape {
+    /// Nothing is sent back, e.g. because the board reboots.
+    None,
+    /// A single `OK`.
+    Ok,
+    /// A single data line with no `OK`.
+    Data,
+    /// A data line followed by `OK`.
+    DataThenOk,
+    /// Any number of data lines terminated by `OK`.
+    LinesThenOk,
+}
+
+impl EbbCommand {
+    /// Whether the command only reads state from the board, and so is safe to run out of order.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            EbbCommand::QueryButton
+                | EbbCommand::QueryCurrent
+                | EbbCommand::QueryEnables
+                | EbbCommand::QueryGeneral
+                | EbbCommand::QueryLayer
+                | EbbCommand::QueryMotors
+                | EbbCommand::QueryNodeCount
+                | EbbCommand::QueryPen
+                | EbbCommand::QueryServoPower
+                | EbbCommand::QueryStepPosition
+                | EbbCommand::QueryNickname
+                | EbbCommand::Version
+        )
+    }
+
+    pub fn response_shape(&self) -> ResponseShape {
+        match self {
+            EbbCommand::Bootloader | EbbCommand::Reboot => ResponseShape::None,
+            EbbCommand::QueryGeneral | EbbCommand::QueryMotors | EbbCommand::Version => {
+                ResponseShape::Data
+            }
+            EbbCommand::EmergencyStop { .. }
+            | EbbCommand::QueryButton
+            | EbbCommand::QueryCurrent
+            | EbbCommand::QueryEnables
+            | EbbCommand::QueryLayer
+            | EbbCommand::QueryNodeCount
+            | EbbCommand::QueryPen
+            | EbbCommand::QueryServoPower
+            | EbbCommand::QueryStepPosition
+            | EbbCommand::QueryNickname => ResponseShape::DataThenOk,
+            EbbCommand::Check => ResponseShape::LinesThenOk,
+            _ => ResponseShape::Ok,
+        }
+    }
+}
+
+/// A successful reply from the EBB, decoded according to the command that produced it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EbbResponse {
+    Ok,
+    /// Reply to `ES`.
+    Interrupted {
+        interrupted: bool,
+        fifo_steps1: i32,
+        fifo_steps2: i32,
+        remaining_steps1: i32,
+        remaining_steps2: i32,
+    },
+    /// Reply to `QB`.
+    Button {
+        pressed: bool,
+    },
+    /// Reply to `QC`.
+    Current {
+        current: u16,
+        voltage: u16,
+    },
+    /// Reply to `QE`.
+    MotorEnables {
+        motor1: u8,
+        motor2: u8,
+    },
+    /// Reply to `QG`.
+    General(u8),
+    /// Reply to `QL`.
+    Layer(u8),
+    /// Reply to `QM`.
+    Motors {
+        command_executing: bool,
+        motor1_moving: bool,
+        motor2_moving: bool,
+        fifo_pending: bool,
+    },
+    /// Reply to `QN`.
+    NodeCount(u32),
+    /// Reply to `QP`.
+    Pen(PenState),
+    /// Reply to `QR`.
+    ServoPower(bool),
+    /// Reply to `QS`.
+    StepPosition {
+        motor1: i32,
+        motor2: i32,
+    },
+    /// Reply to `QT`.
+    Nickname(String),
+    /// Reply to `V`.
+    Version(String),
+    /// Reply to `CK`.
+    Lines(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EbbError {
+    /// An error reported by the EBB firmware, e.g. `!8 Err: Unknown command`.
+    Firmware(String),
+    /// A reply that does not match what the command should produce.
+    UnexpectedResponse {
+        command: &'static str,
+        response: String,
+    },
+    /// The serial port failed, usually because the EBB was unplugged.
+    Disconnected(String),
+    /// The serial port failed after a command was written but before its reply, so it may or may
+    /// not have run.
+    Unanswered(String),
+    /// Not sent, because an emergency stop has been sent and not yet dealt with.
+    EmergencyStopped,
+    /// The `V` reply of a board that is not an EBB, or whose firmware is too old.
+    UnsupportedFirmware(String),
+}
+
+impl Display for EbbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EbbError::Firmware(error) => write!(f, "{}", error),
+            EbbError::UnexpectedResponse { command, response } => {
+                write!(f, "unexpected response to {}: '{}'", command, response)
+            }
+            EbbError::Disconnected(error) => write!(f, "serial port disconnected: {}", error),
+            EbbError::Unanswered(error) => {
+                write!(f, "serial port disconnected before replying: {}", error)
+            }
+            EbbError::EmergencyStopped => write!(f, "emergency stop in progress"),
+            EbbError::UnsupportedFirmware(version) => {
+                let (major, minor, patch) = MINIMUM_FIRMWARE_VERSION;
+                write!(
+                    f,
+                    "unsupported firmware '{}', need EBB firmware {}.{}.{} or later",
+                    version, major, minor, patch
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for EbbError {}
+
+/// Consumes exactly the lines `command` produces from `next_line` and decodes them.
+///
+/// `next_line` must return one line at a time with line terminators stripped. Firmware errors
+/// end the response early, since the EBB does not follow them with `OK`.
+pub fn read_response(
+    command: &EbbCommand,
+    mut next_line: impl FnMut() -> Result<String, EbbError>,
+) -> Result<EbbResponse, EbbError> {
+    let mut next_line = || {
+        let line = next_line()?;
+        if line.starts_with('!') {
+            Err(EbbError::Firmware(line))
+        } else {
+            Ok(line)
+        }
+    };
+    let unexpected = |response: String| EbbError::UnexpectedResponse {
+        command: command.name(),
+        response,
+    };
+
+    let data = match command.response_shape() {
+        ResponseShape::None => return Ok(EbbResponse::Ok),
+        ResponseShape::Ok => {
+            let line = next_line()?;
+            return if line == "OK" {
+                Ok(EbbResponse::Ok)
+            } else {
+                Err(unexpected(line))
+            };
+        }
+        ResponseShape::Data => vec![next_line()?],
+        ResponseShape::DataThenOk => {
+            let line = next_line()?;
+            // An empty data line (e.g. no nickname set) is indistinguishable from a missing one.
+            if line == "OK" {
+                vec![String::new()]
+            } else {
+                let ok = next_line()?;
+                if ok != "OK" {
+                    return Err(unexpected(format!("{}\n{}", line, ok)));
+                }
+                vec![line]
+            }
+        }
+        ResponseShape::LinesThenOk => {
+            let mut lines = Vec::new();
+            loop {
+                let line = next_line()?;
+                if line == "OK" {
+                    break lines;
+                }
+                lines.push(line);
+            }
+        }
+    };
+
+    decode_data(command, data).map_err(|data| unexpected(data.join("\n")))
+}
+
+fn decode_data(command: &EbbCommand, data: Vec<String>) -> Result<EbbResponse, Vec<String>> {
+    let fields = |expected: usize| -> Option<Vec<i64>> {
+        let fields = data[0]
+            .split(',')
+            .map(|field| field.trim().parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        (fields.len() == expected).then_some(fields)
+    };
+
+    let response = match command {
+        EbbCommand::EmergencyStop { .. } => fields(5).map(|f| EbbResponse::Interrupted {
+            interrupted: f[0] != 0,
+            fifo_steps1: f[1] as i32,
+            fifo_steps2: f[2] as i32,
+            remaining_steps1: f[3] as i32,
+            remaining_steps2: f[4] as i32,
+        }),
+        EbbCommand::QueryButton => fields(1).map(|f| EbbResponse::Button { pressed: f[0] != 0 }),
+        EbbCommand::QueryCurrent => fields(2).map(|f| EbbResponse::Current {
+            current: f[0] as u16,
+            voltage: f[1] as u16,
+        }),
+        EbbCommand::QueryEnables => fields(2).map(|f| EbbResponse::MotorEnables {
+            motor1: f[0] as u8,
+            motor2: f[1] as u8,
+        }),
+        EbbCommand::QueryGeneral => u8::from_str_radix(data[0].trim(), 16)
+            .ok()
+            .map(EbbResponse::General),
+        EbbCommand::QueryLayer => fields(1).map(|f| EbbResponse::Layer(f[0] as u8)),
+        EbbCommand::QueryMotors => {
+            // Firmware before 2.4.4 omits the FIFO status.
+            let f = data[0]
+                .strip_prefix("QM,")
+                .and_then(|rest| {
+                    rest.split(',')
+                        .map(|field| field.trim().parse::<u8>().ok())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .filter(|f| f.len() == 3 || f.len() == 4);
+            f.map(|f| EbbResponse::Motors {
+                command_executing: f[0] != 0,
+                motor1_moving: f[1] != 0,
+                motor2_moving: f[2] != 0,
+                fifo_pending: f.get(3).is_some_and(|v| *v != 0),
+            })
+        }
+        EbbCommand::QueryNodeCount => fields(1).map(|f| EbbResponse::NodeCount(f[0] as u32)),
+        EbbCommand::QueryPen => fields(1).map(|f| {
+            EbbResponse::Pen(if f[0] != 0 {
+                PenState::Up
+            } else {
+                PenState::Down
+            })
+        }),
+        EbbCommand::QueryServoPower => fields(1).map(|f| EbbResponse::ServoPower(f[0] != 0)),
+        EbbCommand::QueryStepPosition => fields(2).map(|f| EbbResponse::StepPosition {
+            motor1: f[0] as i32,
+            motor2: f[1] as i32,
+        }),
+        EbbCommand::QueryNickname => Some(EbbResponse::Nickname(data[0].clone())),
+        EbbCommand::Version => Some(EbbResponse::Version(data[0].clone())),
+        EbbCommand::Check => Some(EbbResponse::Lines(data.clone())),
+        _ => None,
+    };
+
+    response.ok_or(data)
+}
+
+/// Extracts the version number from a `V` reply such as
+/// `EBBv13_and_above EB Firmware Version 3.0.2`.
+pub fn firmware_version(reply: &str) -> Option<(u32, u32, u32)> {
+    let (_, version) = reply.rsplit_once("Firmware Version ")?;
+    let mut parts = version
+        .trim()
+        .split('.')
+        .map(|part| part.parse::<u32>().ok());
+
+    Some((
+        parts.next()??,
+        parts.next().flatten().unwrap_or_default(),
+        parts.next().flatten().unwrap_or_default(),
+    ))
+}
+
+/// Microstep resolution selected by an `EM` enable value, or `None` if it disables the motor.
+pub fn microstep_resolution(enable: u8) -> Option<u8> {
+    match enable {
+        0 => None,
+        1 => Some(16),
+        2 => Some(8),
+        3 => Some(4),
+        4 => Some(2),
+        _ => Some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every command, in canonical form, with and without its optional arguments.
+    const CANONICAL: &[&str] = &[
+        "AC,3,1",
+        "BL",
+        "CK",
+        "CS",
+        "CU,1,-5",
+        "EM,1",
+        "EM,2,0",
+        "ES",
+        "ES,1",
+        "HM,2000",
+        "HM,2000,-100,250",
+        "LM,85899345,100,0,0,0,0",
+        "LM,1000,-200,10,2000,300,-5,3",
+        "LT,25000,429496,0,-429496,0",
+        "LT,100,1,2,3,4,2",
+        "ND",
+        "NI",
+        "QB",
+        "QC",
+        "QE",
+        "QG",
+        "QL",
+        "QM",
+        "QN",
+        "QP",
+        "QR",
+        "QS",
+        "QT",
+        "R",
+        "RB",
+        "S2,7500,4",
+        "S2,7500,4,50",
+        "S2,7500,4,0,100",
+        "SC,4,16000",
+        "SE,1",
+        "SE,1,512,1",
+        "SL,5",
+        "SM,10,250",
+        "SM,100,800,-800",
+        "SN,123456",
+        "SP,1",
+        "SP,0,150",
+        "SP,1,150,3",
+        "SR,60000",
+        "SR,60000,1",
+        "ST,axidraw",
+        "TP",
+        "TP,500",
+        "V",
+        "XM,100,300,-200",
+    ];
+
+    fn parse(command: &str) -> EbbCommand {
+        command
+            .parse()
+            .unwrap_or_else(|e| panic!("'{}' did not parse: {}", command, e))
+    }
+
+    #[test]
+    fn commands_round_trip_through_display() {
+        for canonical in CANONICAL {
+            let command = parse(canonical);
+
+            assert_eq!(command.to_string(), *canonical);
+            assert_eq!(parse(&command.to_string()), command);
+        }
+    }
+
+    #[test]
+    fn every_known_command_is_round_tripped() {
+        for name in KNOWN_COMMANDS {
+            assert!(
+                CANONICAL
+                    .iter()
+                    .any(|canonical| parse(canonical).name() == *name),
+                "{} is not covered",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn commands_are_normalized() {
+        assert_eq!(parse(" sm, 10 ,1,-1 ").to_string(), "SM,10,1,-1");
+        assert_eq!(parse("QS,").to_string(), "QS");
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        let error = |command: &str| command.parse::<EbbCommand>().unwrap_err();
+
+        assert_eq!(error(" "), ParseCommandError::Empty);
+        assert_eq!(error("SM,1\n"), ParseCommandError::InvalidCharacter('\n'));
+        assert_eq!(
+            error("ZZ,1"),
+            ParseCommandError::UnknownCommand("ZZ".to_string())
+        );
+        assert_eq!(
+            error("QS,1"),
+            ParseCommandError::WrongArgumentCount {
+                command: "QS",
+                min: 0,
+                max: 0,
+                found: 1,
+            }
+        );
+        // HM takes a step frequency, optionally followed by both positions.
+        assert_eq!(
+            error("HM,2000,5"),
+            ParseCommandError::WrongArgumentCount {
+                command: "HM",
+                min: 3,
+                max: 3,
+                found: 2,
+            }
+        );
+        assert_eq!(
+            error("SM,ten,1"),
+            ParseCommandError::InvalidArgument {
+                command: "SM",
+                argument: "duration",
+                value: "ten".to_string(),
+            }
+        );
+    }
+
+    fn out_of_range(command: &'static str, argument: &'static str, value: i64, min: i64, max: i64) {
+        assert_eq!(
+            command.parse::<EbbCommand>(),
+            Err(ParseCommandError::OutOfRange {
+                command: &command[..2],
+                argument,
+                value,
+                min,
+                max,
+            }),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn arguments_outside_their_ranges_are_rejected() {
+        out_of_range("SM,0,1", "duration", 0, 1, MAX_MOVE_VALUE);
+        out_of_range(
+            "SM,1000,16777216",
+            "steps1",
+            16_777_216,
+            -MAX_MOVE_VALUE,
+            MAX_MOVE_VALUE,
+        );
+        out_of_range("HM,1", "step_frequency", 1, 2, 25_000);
+        out_of_range(
+            "HM,2000,4294968,0",
+            "position1",
+            4_294_968,
+            -4_294_967,
+            4_294_967,
+        );
+        out_of_range("EM,6", "enable1", 6, 0, 5);
+        out_of_range("EM,1,2", "enable2", 2, 0, 1);
+        out_of_range("SP,2", "value", 2, 0, 1);
+        out_of_range("SP,1,65536", "duration", 65_536, 0, 65_535);
+        out_of_range("SL,128", "layer", 128, 0, 127);
+        out_of_range("SE,1,1024", "power", 1_024, 0, 1_023);
+        out_of_range("LT,0,1,0,1,0", "intervals", 0, 1, i32::MAX.into());
+        out_of_range("ST,seventeen-letters", "nickname length", 17, 0, 16);
+    }
+
+    #[test]
+    fn moves_faster_than_the_ebb_can_step_are_rejected() {
+        assert_eq!(
+            "SM,10,1,-300".parse::<EbbCommand>(),
+            Err(ParseCommandError::StepRateTooHigh {
+                command: "SM",
+                steps: -300,
+                duration: 10,
+            })
+        );
+        // Each motor moves by the sum or difference of the A and B steps.
+        assert_eq!(
+            "XM,10,200,100".parse::<EbbCommand>(),
+            Err(ParseCommandError::StepRateTooHigh {
+                command: "XM",
+                steps: 300,
+                duration: 10,
+            })
+        );
+        assert_eq!(
+            "SM,10,1,-300"
+                .parse::<EbbCommand>()
+                .unwrap_err()
+                .to_string(),
+            "SM: -300 steps in 10 ms exceeds the maximum rate of 25 steps/ms"
+        );
+        assert!("SM,10,250,-250".parse::<EbbCommand>().is_ok());
+    }
+
+    /// Reads the response to `command` from `lines`, returning it and how many lines were left.
+    fn respond(command: &str, lines: &[&str]) -> (Result<EbbResponse, EbbError>, usize) {
+        let mut lines = lines.iter();
+        let response = read_response(&parse(command), || {
+            lines
+                .next()
+                .map(|line| line.to_string())
+                .ok_or_else(|| EbbError::Disconnected("no more lines".to_string()))
+        });
+
+        (response, lines.len())
+    }
+
+    fn firmware_error() -> EbbError {
+        EbbError::Firmware("!8 Err: Unknown command".to_string())
+    }
+
+    #[test]
+    fn commands_without_a_response_read_nothing() {
+        assert_eq!(respond("RB", &["OK"]), (Ok(EbbResponse::Ok), 1));
+        assert_eq!(respond("BL", &[]), (Ok(EbbResponse::Ok), 0));
+    }
+
+    #[test]
+    fn ok_responses_are_a_single_line() {
+        assert_eq!(
+            respond("SM,10,1,1", &["OK", "OK"]),
+            (Ok(EbbResponse::Ok), 1)
+        );
+        assert_eq!(
+            respond("SP,1", &["!8 Err: Unknown command", "OK"]),
+            (Err(firmware_error()), 1)
+        );
+        assert_eq!(
+            respond("SP,1", &["1"]),
+            (
+                Err(EbbError::UnexpectedResponse {
+                    command: "SP",
+                    response: "1".to_string(),
+                }),
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn data_responses_have_no_ok() {
+        assert_eq!(
+            respond("QG", &["3E", "OK"]),
+            (Ok(EbbResponse::General(0x3E)), 1)
+        );
+        assert_eq!(
+            respond("QM", &["QM,0,1,0,1"]),
+            (
+                Ok(EbbResponse::Motors {
+                    command_executing: false,
+                    motor1_moving: true,
+                    motor2_moving: false,
+                    fifo_pending: true,
+                }),
+                0
+            )
+        );
+        // Older firmware leaves out the FIFO status.
+        assert_eq!(
+            respond("QM", &["QM,1,0,0"]),
+            (
+                Ok(EbbResponse::Motors {
+                    command_executing: true,
+                    motor1_moving: false,
+                    motor2_moving: false,
+                    fifo_pending: false,
+                }),
+                0
+            )
+        );
+        assert_eq!(
+            respond("V", &["EBBv13_and_above EB Firmware Version 3.0.2"]),
+            (
+                Ok(EbbResponse::Version(
+                    "EBBv13_and_above EB Firmware Version 3.0.2".to_string()
+                )),
+                0
+            )
+        );
+        assert_eq!(
+            respond("QG", &["!8 Err: Unknown command"]),
+            (Err(firmware_error()), 0)
+        );
+        assert_eq!(
+            respond("QG", &["XYZ"]),
+            (
+                Err(EbbError::UnexpectedResponse {
+                    command: "QG",
+                    response: "XYZ".to_string(),
+                }),
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn data_then_ok_responses_are_two_lines() {
+        assert_eq!(
+            respond("QS", &["100,-200", "OK", "OK"]),
+            (
+                Ok(EbbResponse::StepPosition {
+                    motor1: 100,
+                    motor2: -200,
+                }),
+                1
+            )
+        );
+        assert_eq!(
+            respond("ES", &["1,5,6,7,8", "OK"]),
+            (
+                Ok(EbbResponse::Interrupted {
+                    interrupted: true,
+                    fifo_steps1: 5,
+                    fifo_steps2: 6,
+                    remaining_steps1: 7,
+                    remaining_steps2: 8,
+                }),
+                0
+            )
+        );
+        assert_eq!(
+            respond("QC", &["0394,0300", "OK"]),
+            (
+                Ok(EbbResponse::Current {
+                    current: 394,
+                    voltage: 300,
+                }),
+                0
+            )
+        );
+        assert_eq!(
+            respond("QP", &["0", "OK"]),
+            (Ok(EbbResponse::Pen(PenState::Down)), 0)
+        );
+        // No nickname set sends an empty line, which may be lost.
+        assert_eq!(
+            respond("QT", &["OK", "OK"]),
+            (Ok(EbbResponse::Nickname(String::new())), 1)
+        );
+        assert_eq!(
+            respond("QS", &["!8 Err: Unknown command", "OK"]),
+            (Err(firmware_error()), 1)
+        );
+        assert_eq!(
+            respond("QS", &["100,-200", "100,-200"]),
+            (
+                Err(EbbError::UnexpectedResponse {
+                    command: "QS",
+                    response: "100,-200\n100,-200".to_string(),
+                }),
+                0
+            )
+        );
+        assert_eq!(
+            respond("QS", &["100", "OK"]),
+            (
+                Err(EbbError::UnexpectedResponse {
+                    command: "QS",
+                    response: "100".to_string(),
+                }),
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn lines_then_ok_responses_read_up_to_ok() {
+        assert_eq!(
+            respond("CK", &["Param1=0", "Param2=0", "OK", "OK"]),
+            (
+                Ok(EbbResponse::Lines(vec![
+                    "Param1=0".to_string(),
+                    "Param2=0".to_string(),
+                ])),
+                1
+            )
+        );
+        assert_eq!(
+            respond("CK", &["Param1=0", "!8 Err: Unknown command", "OK"]),
+            (Err(firmware_error()), 1)
+        );
+    }
+
+    #[test]
+    fn firmware_versions_are_read_from_the_version_reply() {
+        assert_eq!(
+            firmware_version("EBBv13_and_above EB Firmware Version 3.0.2"),
+            Some((3, 0, 2))
+        );
+        assert_eq!(
+            firmware_version("EBBv13_and_above EB Firmware Version 2.5"),
+            Some((2, 5, 0))
+        );
+        assert_eq!(firmware_version("Not an EBB"), None);
+    }
+}
@@ -0,0 +1,771 @@
+use crate::{
+    axidraw_over_http::RunningStatus,
+    axidraw_over_tcp::{
+        Bounds, CarriagePosition, DryRunReport, EmergencyStopReply, HomeDistance, ImageFormat,
+        JobInfo,
+    },
+    client_address,
+    dry_run::DryRun,
+    dry_run_report,
+    ebb::EbbCommand,
+    home_step_frequency,
+    job::JobError,
+    parse_command, AxidrawService, SHUTTING_DOWN,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::broadcast::error::RecvError;
+use warp::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    hyper::body::Bytes,
+    reply::{json, with_status, Response},
+    ws::{Message, WebSocket, Ws},
+    Filter, Reply,
+};
+
+#[derive(Deserialize)]
+struct CommandsRequest {
+    name: Option<String>,
+    commands: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    id: u64,
+    name: String,
+    total_commands: u64,
+    executed_commands: u64,
+    open: bool,
+    client: String,
+    submitted_at_ms: u64,
+    elapsed_ms: u64,
+    remaining_ms: u64,
+}
+
+impl From<JobInfo> for JobResponse {
+    fn from(job: JobInfo) -> Self {
+        JobResponse {
+            id: job.id,
+            name: job.name,
+            total_commands: job.total_commands,
+            executed_commands: job.executed_commands,
+            open: job.open,
+            client: job.client,
+            submitted_at_ms: job.submitted_at_ms,
+            elapsed_ms: job.elapsed_ms,
+            remaining_ms: job.remaining_ms,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MoveJobRequest {
+    offset: i64,
+}
+
+#[derive(Deserialize)]
+struct EmergencyStopQuery {
+    #[serde(default)]
+    clear_queue: bool,
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    #[serde(default)]
+    format: PreviewFormat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum PreviewFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+impl From<PreviewFormat> for ImageFormat {
+    fn from(format: PreviewFormat) -> Self {
+        match format {
+            PreviewFormat::Svg => ImageFormat::Svg,
+            PreviewFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewRequest {
+    commands: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BoundsResponse {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl From<Bounds> for BoundsResponse {
+    fn from(bounds: Bounds) -> Self {
+        BoundsResponse {
+            min_x: bounds.min_x,
+            min_y: bounds.min_y,
+            max_x: bounds.max_x,
+            max_y: bounds.max_y,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct InvalidCommandResponse {
+    index: u64,
+    command: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct DryRunResponse {
+    duration_ms: u64,
+    pen_down_distance_steps: f64,
+    pen_down_distance_mm: f64,
+    bounds_steps: BoundsResponse,
+    bounds_mm: BoundsResponse,
+    pen_lifts: u64,
+    invalid_commands: Vec<InvalidCommandResponse>,
+}
+
+impl From<DryRunReport> for DryRunResponse {
+    fn from(report: DryRunReport) -> Self {
+        DryRunResponse {
+            duration_ms: report.duration_ms,
+            pen_down_distance_steps: report.pen_down_distance_steps,
+            pen_down_distance_mm: report.pen_down_distance_mm,
+            bounds_steps: report.bounds_steps.unwrap_or_default().into(),
+            bounds_mm: report.bounds_mm.unwrap_or_default().into(),
+            pen_lifts: report.pen_lifts,
+            invalid_commands: report
+                .invalid_commands
+                .into_iter()
+                .map(|invalid| InvalidCommandResponse {
+                    index: invalid.index,
+                    command: invalid.command,
+                    error: invalid.error,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EmergencyStopResponse {
+    interrupted: bool,
+    fifo_steps1: i32,
+    fifo_steps2: i32,
+    remaining_steps1: i32,
+    remaining_steps2: i32,
+    motor1: i32,
+    motor2: i32,
+}
+
+impl From<EmergencyStopReply> for EmergencyStopResponse {
+    fn from(reply: EmergencyStopReply) -> Self {
+        let position = reply.position.unwrap_or_default();
+
+        EmergencyStopResponse {
+            interrupted: reply.interrupted,
+            fifo_steps1: reply.fifo_steps1,
+            fifo_steps2: reply.fifo_steps2,
+            remaining_steps1: reply.remaining_steps1,
+            remaining_steps2: reply.remaining_steps2,
+            motor1: position.motor1,
+            motor2: position.motor2,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SvgQuery {
+    name: Option<String>,
+    #[serde(default)]
+    paper_width_mm: f64,
+    #[serde(default)]
+    paper_height_mm: f64,
+}
+
+#[derive(Deserialize)]
+struct ReturnHomeQuery {
+    #[serde(default)]
+    step_frequency: u32,
+    #[serde(default)]
+    ignore_origin: bool,
+}
+
+#[derive(Serialize)]
+struct CarriageResponse {
+    x_steps: f64,
+    y_steps: f64,
+    x_mm: f64,
+    y_mm: f64,
+    microstepping: u32,
+}
+
+impl From<CarriagePosition> for CarriageResponse {
+    fn from(carriage: CarriagePosition) -> Self {
+        CarriageResponse {
+            x_steps: carriage.x_steps,
+            y_steps: carriage.y_steps,
+            x_mm: carriage.x_mm,
+            y_mm: carriage.y_mm,
+            microstepping: carriage.microstepping,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HomeDistanceResponse {
+    position: CarriageResponse,
+    origin: CarriageResponse,
+    distance_from_home_mm: f64,
+    distance_from_origin_mm: f64,
+}
+
+impl From<HomeDistance> for HomeDistanceResponse {
+    fn from(distance: HomeDistance) -> Self {
+        HomeDistanceResponse {
+            position: distance.position.unwrap_or_default().into(),
+            origin: distance.origin.unwrap_or_default().into(),
+            distance_from_home_mm: distance.distance_from_home_mm,
+            distance_from_origin_mm: distance.distance_from_origin_mm,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CommandErrorResponse {
+    command: String,
+    error: String,
+}
+
+/// A message sent by a WebSocket client.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Commands {
+        commands: Vec<String>,
+    },
+    Pause,
+    Resume,
+    Clear,
+    EmergencyStop {
+        #[serde(default)]
+        clear_queue: bool,
+    },
+}
+
+/// A message pushed to WebSocket clients.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    State(Box<StateResponse>),
+    Error { error: String },
+}
+
+#[derive(Serialize)]
+struct PositionResponse {
+    motor1: i32,
+    motor2: i32,
+    #[serde(flatten)]
+    carriage: CarriageResponse,
+    pen_down: bool,
+}
+
+#[derive(Serialize)]
+struct ProgressResponse {
+    executed_commands: u64,
+    elapsed_ms: u64,
+    remaining_ms: u64,
+    percent_complete: f64,
+}
+
+#[derive(Serialize)]
+struct StateResponse {
+    buffer_length: u64,
+    running_status: &'static str,
+    last_command: Option<String>,
+    last_error: Option<CommandErrorResponse>,
+    current_job: Option<JobResponse>,
+    queued_jobs: Vec<JobResponse>,
+    position: PositionResponse,
+    progress: ProgressResponse,
+}
+
+/// Serves the same operations as the gRPC service as JSON endpoints:
+///
+/// - `POST /commands` with `{"name": "...", "commands": ["SM,1000,200,200", ...]}`, queued as
+///   one job
+/// - `POST /dry-run` with the same body, to time and check the commands without queueing them
+/// - `POST /svg` with an SVG document as the body, drawn as one job scaled to fit the paper;
+///   `?name=...`, and `?paper_width_mm=...&paper_height_mm=...` for other paper
+/// - `POST /clear`, `POST /pause`, `POST /resume`
+/// - `POST /recover` to home and return to the last acknowledged position, then resume; after a
+///   power cycle, move the carriage to home by hand first
+/// - `POST /estop` to stop the motors at once, with `?clear_queue=true` to also clear the queue
+/// - `POST /home` to raise the pen and go back to the user origin between queued commands, with
+///   `?step_frequency=2000` to set the speed and `?ignore_origin=true` to go home instead
+/// - `GET /home` for the distance from home and from the user origin
+/// - `POST /origin` to make the current position the user origin, `DELETE /origin` to put it
+///   back at home
+/// - `GET /state`
+/// - `GET /preview` to draw what the queue will plot, or `POST /preview` with
+///   `{"commands": [...]}` to draw what those commands would plot from home; `?format=png` for a
+///   PNG rather than an SVG
+/// - `GET /jobs`, `DELETE /jobs/{id}`, `POST /jobs/{id}/move` with `{"offset": -1}`
+///
+/// `GET /ws` upgrades to a WebSocket that accepts `{"type": "commands", "commands": [...]}`,
+/// `{"type": "pause"}`, `{"type": "resume"}`, `{"type": "clear"}` and
+/// `{"type": "emergency_stop", "clear_queue": false}` messages, and pushes
+/// `{"type": "state", ...}` whenever the state changes. Each `commands` message is queued as a
+/// job of its own, so a socket left open does not hold up the queue.
+pub async fn serve(service: Arc<AxidrawService>, port: u16) {
+    let shutdown_service = service.clone();
+    let with_service = warp::any().map(move || service.clone());
+
+    let commands = warp::path!("commands")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::addr::remote())
+        .and(warp::body::json())
+        .then(enqueue_commands);
+
+    let dry_run = warp::path!("dry-run")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::body::json())
+        .map(|service: Arc<AxidrawService>, request: CommandsRequest| {
+            let report = dry_run_report(&DryRun::run(
+                &request.commands,
+                &service.scale,
+                service.limits.as_ref(),
+            ));
+            json(&DryRunResponse::from(report))
+        });
+
+    let svg = warp::path!("svg")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::addr::remote())
+        .and(warp::query())
+        .and(warp::body::bytes())
+        .then(enqueue_svg);
+
+    let jobs = warp::path!("jobs")
+        .and(warp::get())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            let jobs = service.jobs().await;
+            json(&jobs.into_iter().map(JobResponse::from).collect::<Vec<_>>())
+        });
+
+    let cancel_job = warp::path!("jobs" / u64)
+        .and(warp::delete())
+        .and(with_service.clone())
+        .then(|job_id, service: Arc<AxidrawService>| async move {
+            job_reply(service.remove_job(job_id).await)
+        });
+
+    let move_job = warp::path!("jobs" / u64 / "move")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::body::json())
+        .then(
+            |job_id, service: Arc<AxidrawService>, request: MoveJobRequest| async move {
+                job_reply(service.reorder_job(job_id, request.offset).await)
+            },
+        );
+
+    let clear = warp::path!("clear")
+        .and(warp::post())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            service.clear_buffer().await;
+            StatusCode::NO_CONTENT
+        });
+
+    let pause = warp::path!("pause")
+        .and(warp::post())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            service.pause_buffer().await;
+            StatusCode::NO_CONTENT
+        });
+
+    let resume = warp::path!("resume")
+        .and(warp::post())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            match service.resume_buffer().await {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(error) => with_status(json(&ErrorResponse { error }), StatusCode::CONFLICT)
+                    .into_response(),
+            }
+        });
+
+    let recover = warp::path!("recover")
+        .and(warp::post())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            service.recover().await;
+            StatusCode::NO_CONTENT
+        });
+
+    let emergency_stop = warp::path!("estop")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::query())
+        .then(
+            |service: Arc<AxidrawService>, query: EmergencyStopQuery| async move {
+                match service.emergency_stop(query.clear_queue).await {
+                    Ok(reply) => json(&EmergencyStopResponse::from(reply)).into_response(),
+                    Err(error) => with_status(
+                        json(&ErrorResponse { error }),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response(),
+                }
+            },
+        );
+
+    let return_home = warp::path!("home")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::query())
+        .then(
+            |service: Arc<AxidrawService>, query: ReturnHomeQuery| async move {
+                let step_frequency = match home_step_frequency(query.step_frequency) {
+                    Ok(step_frequency) => step_frequency,
+                    Err(error) => {
+                        return with_status(json(&ErrorResponse { error }), StatusCode::BAD_REQUEST)
+                            .into_response()
+                    }
+                };
+                match service
+                    .return_home(step_frequency, query.ignore_origin)
+                    .await
+                {
+                    Ok(distance) => json(&HomeDistanceResponse::from(distance)).into_response(),
+                    Err(error) => with_status(
+                        json(&ErrorResponse { error }),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response(),
+                }
+            },
+        );
+
+    let home_distance = warp::path!("home")
+        .and(warp::get())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            json(&HomeDistanceResponse::from(service.home_distance().await))
+        });
+
+    let set_origin = warp::path!("origin")
+        .and(warp::post())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            json(&HomeDistanceResponse::from(service.set_origin(false).await))
+        });
+
+    let reset_origin = warp::path!("origin")
+        .and(warp::delete())
+        .and(with_service.clone())
+        .then(|service: Arc<AxidrawService>| async move {
+            json(&HomeDistanceResponse::from(service.set_origin(true).await))
+        });
+
+    let state = warp::path!("state")
+        .and(warp::get())
+        .and(with_service.clone())
+        .then(get_state);
+
+    let preview_queue = warp::path!("preview")
+        .and(warp::get())
+        .and(with_service.clone())
+        .and(warp::query())
+        .then(
+            |service: Arc<AxidrawService>, query: PreviewQuery| async move {
+                preview(service, query, PreviewRequest { commands: vec![] }).await
+            },
+        );
+
+    let preview_commands = warp::path!("preview")
+        .and(warp::post())
+        .and(with_service.clone())
+        .and(warp::query())
+        .and(warp::body::json())
+        .then(preview);
+
+    let websocket = warp::path!("ws")
+        .and(warp::ws())
+        .and(with_service)
+        .and(warp::addr::remote())
+        .map(
+            |ws: Ws, service: Arc<AxidrawService>, address: Option<SocketAddr>| {
+                ws.on_upgrade(move |socket| handle_websocket(service, address, socket))
+            },
+        );
+
+    let routes = commands
+        .or(dry_run)
+        .or(svg)
+        .or(jobs)
+        .or(cancel_job)
+        .or(move_job)
+        .or(clear)
+        .or(pause)
+        .or(resume)
+        .or(recover)
+        .or(emergency_stop)
+        .or(return_home)
+        .or(home_distance)
+        .or(set_origin)
+        .or(reset_origin)
+        .or(state)
+        .or(preview_queue)
+        .or(preview_commands)
+        .or(websocket);
+
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown((IpAddr::from_str("::").unwrap(), port), async move {
+            shutdown_service.shutdown_started().await
+        });
+    server.await;
+}
+
+/// Validates the whole batch before any of it is buffered, so a bad line cannot leave half a
+/// drawing queued.
+fn parse_commands(contents: &[String]) -> Result<Vec<EbbCommand>, String> {
+    contents
+        .iter()
+        .map(|contents| parse_command(contents))
+        .collect()
+}
+
+async fn enqueue_commands(
+    service: Arc<AxidrawService>,
+    address: Option<SocketAddr>,
+    request: CommandsRequest,
+) -> Response {
+    let commands = match parse_commands(&request.commands) {
+        Ok(commands) => commands,
+        Err(error) => {
+            return with_status(json(&ErrorResponse { error }), StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+
+    if service.is_shutting_down() {
+        let error = SHUTTING_DOWN.to_string();
+        return with_status(
+            json(&ErrorResponse { error }),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+        .into_response();
+    }
+
+    let job = service
+        .queue_job(request.name, client_address(address), commands)
+        .await;
+
+    json(&JobResponse::from(job)).into_response()
+}
+
+async fn enqueue_svg(
+    service: Arc<AxidrawService>,
+    address: Option<SocketAddr>,
+    query: SvgQuery,
+    body: Bytes,
+) -> Response {
+    let Ok(svg) = std::str::from_utf8(&body) else {
+        let error = "SVG document is not valid UTF-8".to_string();
+        return with_status(json(&ErrorResponse { error }), StatusCode::BAD_REQUEST)
+            .into_response();
+    };
+
+    if service.is_shutting_down() {
+        let error = SHUTTING_DOWN.to_string();
+        return with_status(
+            json(&ErrorResponse { error }),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+        .into_response();
+    }
+
+    let paper = (query.paper_width_mm, query.paper_height_mm);
+    match service
+        .queue_svg(query.name, client_address(address), svg, paper)
+        .await
+    {
+        Ok(job) => json(&JobResponse::from(job)).into_response(),
+        Err(error) => {
+            with_status(json(&ErrorResponse { error }), StatusCode::BAD_REQUEST).into_response()
+        }
+    }
+}
+
+async fn preview(
+    service: Arc<AxidrawService>,
+    query: PreviewQuery,
+    request: PreviewRequest,
+) -> Response {
+    let commands = match parse_commands(&request.commands) {
+        Ok(commands) => commands,
+        Err(error) => {
+            return with_status(json(&ErrorResponse { error }), StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+
+    let (image, content_type) = service.preview(commands).await.render(query.format.into());
+
+    warp::reply::with_header(image, CONTENT_TYPE, content_type).into_response()
+}
+
+fn job_reply(result: Result<(), JobError>) -> Response {
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => {
+            let status = match error {
+                JobError::NotFound(_) => StatusCode::NOT_FOUND,
+                JobError::Started(_) => StatusCode::CONFLICT,
+            };
+            let error = error.to_string();
+            with_status(json(&ErrorResponse { error }), status).into_response()
+        }
+    }
+}
+
+async fn get_state(service: Arc<AxidrawService>) -> impl Reply {
+    json(&state_response(&service).await)
+}
+
+async fn state_response(service: &AxidrawService) -> StateResponse {
+    let state = service.plotter_state().await;
+    let buffer_state = state.buffer_state.unwrap_or_default();
+    let position = state.position.unwrap_or_default();
+    let progress = state.progress.unwrap_or_default();
+
+    StateResponse {
+        buffer_length: buffer_state.buffer_length,
+        running_status: RunningStatus::try_from(buffer_state.running_status)
+            .unwrap_or(RunningStatus::Paused)
+            .as_str_name(),
+        last_command: Some(state.last_command).filter(|command| !command.is_empty()),
+        last_error: state.last_error.map(|e| CommandErrorResponse {
+            command: e.command,
+            error: e.error,
+        }),
+        current_job: state.current_job.map(JobResponse::from),
+        queued_jobs: state
+            .queued_jobs
+            .into_iter()
+            .map(JobResponse::from)
+            .collect(),
+        position: PositionResponse {
+            motor1: position.motor1,
+            motor2: position.motor2,
+            carriage: state.carriage.unwrap_or_default().into(),
+            pen_down: state.pen_down,
+        },
+        progress: ProgressResponse {
+            executed_commands: progress.executed_commands,
+            elapsed_ms: progress.elapsed_ms,
+            remaining_ms: progress.remaining_ms,
+            percent_complete: progress.percent_complete,
+        },
+    }
+}
+
+async fn handle_websocket(
+    service: Arc<AxidrawService>,
+    address: Option<SocketAddr>,
+    socket: WebSocket,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let mut state_changes = service.state_change_sender.subscribe();
+
+    let mut reply = Some(ServerMessage::State(Box::new(
+        state_response(&service).await,
+    )));
+
+    loop {
+        if let Some(message) = reply.take() {
+            let text = serde_json::to_string(&message).unwrap();
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+
+        tokio::select! {
+            message = stream.next() => {
+                let message = match message {
+                    Some(Ok(message)) if !message.is_close() => message,
+                    _ => break,
+                };
+
+                // Pings and binary frames carry no commands.
+                // Successful messages are answered by the state change they cause.
+                if let Ok(text) = message.to_str() {
+                    let client = client_address(address);
+                    if let Err(error) = handle_client_message(&service, &client, text).await {
+                        reply = Some(ServerMessage::Error { error });
+                    }
+                }
+            }
+            change = state_changes.recv() => {
+                if let Err(RecvError::Closed) = change {
+                    break;
+                }
+
+                reply = Some(ServerMessage::State(Box::new(state_response(&service).await)));
+            }
+        }
+    }
+}
+
+async fn handle_client_message(
+    service: &AxidrawService,
+    client: &str,
+    text: &str,
+) -> Result<(), String> {
+    let message = serde_json::from_str::<ClientMessage>(text)
+        .map_err(|e| format!("Invalid message: {}", e))?;
+
+    match message {
+        ClientMessage::Commands { .. } if service.is_shutting_down() => {
+            return Err(SHUTTING_DOWN.to_string());
+        }
+        ClientMessage::Commands { commands } => {
+            let commands = parse_commands(&commands)?;
+            service.queue_job(None, client.to_string(), commands).await;
+        }
+        ClientMessage::Pause => service.pause_buffer().await,
+        ClientMessage::Resume => service.resume_buffer().await?,
+        ClientMessage::Clear => service.clear_buffer().await,
+        ClientMessage::EmergencyStop { clear_queue } => {
+            service.emergency_stop(clear_queue).await?;
+        }
+    }
+
+    Ok(())
+}
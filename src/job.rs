@@ -0,0 +1,670 @@
+use crate::{
+    ebb::{EbbCommand, PenState},
+    journal::{Journal, JournalEntry},
+    kinematics,
+    position::Position,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A named batch of commands submitted by a single client, run without interleaving others.
+pub struct Job {
+    pub id: u64,
+    pub name: String,
+    /// Address of the client that submitted the job, if known.
+    pub client: String,
+    pub submitted_at: SystemTime,
+    pub commands: VecDeque<EbbCommand>,
+    pub executed_commands: u64,
+    /// Whether the client is still adding commands. An open job holds the plotter even when it
+    /// has nothing left to run, so a slow stream is not overtaken by the next job.
+    pub open: bool,
+    /// Time spent running the job's commands so far, not counting the one in flight.
+    pub elapsed: Duration,
+    /// Estimated time to run the commands left in `commands`.
+    pub remaining: Duration,
+    /// Where the plotter will be after the last command added, for estimating moves to absolute
+    /// positions.
+    end: Position,
+}
+
+impl Job {
+    fn new(
+        id: u64,
+        name: String,
+        client: String,
+        submitted_at: SystemTime,
+        start: Position,
+    ) -> Job {
+        Job {
+            id,
+            name,
+            client,
+            submitted_at,
+            commands: VecDeque::new(),
+            executed_commands: 0,
+            open: true,
+            elapsed: Duration::ZERO,
+            remaining: Duration::ZERO,
+            end: start,
+        }
+    }
+
+    pub fn total_commands(&self) -> u64 {
+        self.executed_commands + self.commands.len() as u64
+    }
+
+    fn add(&mut self, command: EbbCommand) {
+        self.remaining += kinematics::duration(self.end, &command);
+        self.end = self.end.after(&command);
+        self.commands.push_back(command);
+    }
+
+    /// Takes the next command to run from `from`.
+    fn take(&mut self, from: Position) -> Option<EbbCommand> {
+        let command = self.commands.pop_front()?;
+        self.executed_commands += 1;
+        // Moves to absolute positions may have been estimated from elsewhere, e.g. if the job
+        // was reordered, so the estimate cannot go below zero.
+        self.remaining = self
+            .remaining
+            .saturating_sub(kinematics::duration(from, &command));
+
+        Some(command)
+    }
+
+    fn put_back(&mut self, from: Position, command: EbbCommand) {
+        self.executed_commands -= 1;
+        self.remaining += kinematics::duration(from, &command);
+        self.commands.push_front(command);
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.open && self.commands.is_empty()
+    }
+
+    fn is_started(&self) -> bool {
+        self.executed_commands > 0
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JobError {
+    NotFound(u64),
+    /// The job has started drawing, so it must stay at the front of the queue.
+    Started(u64),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::NotFound(id) => write!(f, "no job with ID {} in the queue", id),
+            JobError::Started(id) => write!(f, "job {} has already started", id),
+        }
+    }
+}
+
+/// Why a command could not be added to a job.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The queue stopped accepting commands for shutdown.
+    Closed,
+    /// The job was cancelled while its client was still adding commands.
+    Cancelled,
+    /// The job is not in the queue, or no longer open, e.g. because the queue was cleared.
+    Removed,
+}
+
+impl Display for PushError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Closed => write!(f, "Queue is closed for shutdown"),
+            PushError::Cancelled => write!(f, "Job was cancelled"),
+            PushError::Removed => write!(f, "Job was removed from the queue"),
+        }
+    }
+}
+
+/// How far through the queued jobs the plotter is. Jobs drop out once they have finished.
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub executed_commands: u64,
+    pub elapsed: Duration,
+    pub remaining: Duration,
+}
+
+impl Progress {
+    pub fn percent_complete(&self) -> f64 {
+        let total = self.elapsed + self.remaining;
+        if total.is_zero() {
+            return 0.0;
+        }
+
+        100.0 * self.elapsed.as_secs_f64() / total.as_secs_f64()
+    }
+}
+
+/// Step rate used to re-home and return to the last position when recovering a plot.
+const RECOVERY_STEP_FREQUENCY: u16 = 2000;
+
+/// Jobs waiting to run, in order. The front job is the one currently running.
+#[derive(Default)]
+pub struct JobQueue {
+    jobs: VecDeque<Job>,
+    next_id: u64,
+    /// Commands that run before anything else, e.g. raising the pen after a job is cancelled.
+    interjections: VecDeque<EbbCommand>,
+    /// The command being executed, the job it came from, if any, and when it was sent.
+    in_flight: Option<(Option<u64>, EbbCommand, Instant)>,
+    position: Position,
+    /// Whether the pen was raised for a pause and should be lowered again on resume. The
+    /// position keeps the pen state the job left it in.
+    parked: bool,
+    /// Set on shutdown, after which no more commands are accepted.
+    closed: bool,
+    /// Jobs cancelled while still open, until their clients close them, so they can be told
+    /// apart from jobs removed by clearing the queue.
+    cancelled: HashSet<u64>,
+    /// Whether the EBB's step counters are known to agree with the tracked position, because it
+    /// has been homed, had its counters cleared or reported where it stopped. Until then, e.g.
+    /// after restoring the journal, they count from wherever the carriage was at power on.
+    homed: bool,
+    /// Set when a command may or may not have run, after which the queue only carries on once
+    /// recovered.
+    position_lost: bool,
+    journal: Option<Journal>,
+}
+
+impl JobQueue {
+    /// Rebuilds the queue recorded in the journal at `path`, then keeps journaling to it.
+    ///
+    /// Jobs that were still open are closed, since their clients went away with the previous
+    /// run. The journal is rewritten to hold only what is left to do.
+    pub fn restore(path: &Path) -> io::Result<JobQueue> {
+        let mut queue = JobQueue::default();
+
+        for entry in Journal::read(path)? {
+            queue.apply(entry);
+        }
+
+        for job in queue.jobs.iter_mut() {
+            job.open = false;
+        }
+        queue.retire_finished();
+        queue.interjections.clear();
+        queue.cancelled.clear();
+
+        queue.journal = Some(Journal::create(path, &queue.snapshot())?);
+
+        Ok(queue)
+    }
+
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Open {
+                id,
+                name,
+                client,
+                submitted_at_ms,
+                executed_commands,
+            } => {
+                self.next_id = self.next_id.max(id);
+                let submitted_at = UNIX_EPOCH + Duration::from_millis(submitted_at_ms);
+                let mut job = Job::new(id, name, client, submitted_at, self.planned_end());
+                job.executed_commands = executed_commands;
+                self.jobs.push_back(job);
+            }
+            JournalEntry::Push { id, command } => match command.parse() {
+                Ok(command) => {
+                    let _ = self.push(id, command);
+                }
+                Err(e) => println!("Dropping journaled command '{}': {}", command, e),
+            },
+            JournalEntry::Close { id } => self.close(id),
+            JournalEntry::Execute { id, position } => {
+                let from = self.position;
+                if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+                    job.take(from);
+                }
+                self.position = position;
+            }
+            JournalEntry::Position { position } => self.position = position,
+            JournalEntry::Cancel { id } => {
+                let _ = self.cancel(id);
+            }
+            JournalEntry::Move { id, offset } => {
+                let _ = self.move_job(id, offset);
+            }
+            JournalEntry::Clear => self.clear(),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<JournalEntry> {
+        let mut entries = vec![JournalEntry::Position {
+            position: self.position,
+        }];
+
+        for job in self.jobs.iter() {
+            entries.push(JournalEntry::Open {
+                id: job.id,
+                name: job.name.clone(),
+                client: job.client.clone(),
+                submitted_at_ms: millis_since_epoch(job.submitted_at),
+                executed_commands: job.executed_commands,
+            });
+            entries.extend(job.commands.iter().map(|command| JournalEntry::Push {
+                id: job.id,
+                command: command.to_string(),
+            }));
+            if !job.open {
+                entries.push(JournalEntry::Close { id: job.id });
+            }
+        }
+
+        entries
+    }
+
+    fn record(&mut self, entry: JournalEntry) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(&entry);
+        }
+    }
+
+    /// Appends a new open job and returns its ID.
+    pub fn open(&mut self, name: Option<String>, client: String) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        let name = name.unwrap_or_else(|| format!("job-{}", id));
+        let job = Job::new(id, name, client, SystemTime::now(), self.planned_end());
+
+        self.record(JournalEntry::Open {
+            id,
+            name: job.name.clone(),
+            client: job.client.clone(),
+            submitted_at_ms: millis_since_epoch(job.submitted_at),
+            executed_commands: 0,
+        });
+        self.jobs.push_back(job);
+
+        id
+    }
+
+    /// Adds a command to an open job.
+    pub fn push(&mut self, id: u64, command: EbbCommand) -> Result<(), PushError> {
+        if self.closed {
+            return Err(PushError::Closed);
+        }
+
+        let Some(job) = self.jobs.iter_mut().find(|job| job.id == id && job.open) else {
+            return Err(if self.cancelled.contains(&id) {
+                PushError::Cancelled
+            } else {
+                PushError::Removed
+            });
+        };
+
+        let entry = JournalEntry::Push {
+            id,
+            command: command.to_string(),
+        };
+        job.add(command);
+        self.record(entry);
+
+        Ok(())
+    }
+
+    /// Marks a job as complete, so it is retired once its last command has run.
+    pub fn close(&mut self, id: u64) {
+        self.cancelled.remove(&id);
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.open = false;
+            self.record(JournalEntry::Close { id });
+        }
+
+        self.retire_finished();
+    }
+
+    /// Stops accepting commands and closes every job, so the queue can run dry.
+    pub fn close_all(&mut self) {
+        self.closed = true;
+
+        let ids = self
+            .jobs
+            .iter()
+            .filter(|job| job.open)
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.close(id);
+        }
+    }
+
+    /// Takes the next command of the running job, if it has one ready. The command is in flight
+    /// until passed to `finish`.
+    pub fn pop(&mut self) -> Option<EbbCommand> {
+        if let Some(command) = self.interjections.pop_front() {
+            self.in_flight = Some((None, command.clone(), Instant::now()));
+            return Some(command);
+        }
+
+        self.retire_finished();
+
+        let job = self.jobs.front_mut()?;
+        let command = job.take(self.position)?;
+
+        self.in_flight = Some((Some(job.id), command.clone(), Instant::now()));
+
+        Some(command)
+    }
+
+    /// Records that the in-flight command has run. Only commands the EBB acknowledged with OK
+    /// move the tracked position.
+    pub fn finish(&mut self, acknowledged: bool) {
+        let Some((id, command, sent_at)) = self.in_flight.take() else {
+            return;
+        };
+
+        if let Some(job) = id.and_then(|id| self.jobs.iter_mut().find(|job| job.id == id)) {
+            job.elapsed += sent_at.elapsed();
+        }
+
+        let position = if acknowledged {
+            self.position.after(&command)
+        } else {
+            self.position
+        };
+
+        if acknowledged && sets_step_counters(&command) {
+            self.homed = true;
+        }
+
+        match id {
+            Some(id) => self.record(JournalEntry::Execute { id, position }),
+            None if position != self.position => self.record(JournalEntry::Position { position }),
+            None => {}
+        }
+
+        self.position = position;
+    }
+
+    /// Puts the in-flight command back to run next, e.g. when the EBB was unplugged before it
+    /// could answer.
+    pub fn retry(&mut self) {
+        let Some((id, command, _)) = self.in_flight.take() else {
+            return;
+        };
+
+        match id.and_then(|id| self.jobs.iter_mut().find(|job| job.id == id)) {
+            Some(job) => job.put_back(self.position, command),
+            // The job was retired while its last command was in flight.
+            None => self.interjections.push_front(command),
+        }
+    }
+
+    /// Where the plotter will be once everything queued so far has run.
+    fn planned_end(&self) -> Position {
+        self.jobs.back().map_or(self.position, |job| job.end)
+    }
+
+    /// Commands run and time spent on the jobs in the queue, and an estimate of the time left,
+    /// counting the command in flight as part way through.
+    pub fn progress(&self) -> Progress {
+        let mut progress = Progress::default();
+
+        for job in self.jobs.iter() {
+            progress.executed_commands += job.executed_commands;
+            progress.elapsed += job.elapsed;
+            progress.remaining += job.remaining;
+        }
+
+        let mut position = self.position;
+        if let Some((id, command, sent_at)) = &self.in_flight {
+            let running_for = sent_at.elapsed();
+            if id.is_some() {
+                progress.elapsed += running_for;
+            }
+            progress.remaining +=
+                kinematics::duration(position, command).saturating_sub(running_for);
+            position = position.after(command);
+        }
+        for command in self.interjections.iter() {
+            progress.remaining += kinematics::duration(position, command);
+            position = position.after(command);
+        }
+
+        progress
+    }
+
+    /// Where the plotter was after the last acknowledged command.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Corrects the tracked position after the plotter moved outside the queue, e.g. because an
+    /// emergency stop abandoned acknowledged moves or it was sent home. The pen was raised and
+    /// stays up.
+    pub fn stopped_at(&mut self, motor1: i32, motor2: i32) {
+        self.parked = false;
+        self.homed = true;
+        self.position = Position {
+            motor1,
+            motor2,
+            pen: PenState::Up,
+            ..self.position
+        };
+        self.record(JournalEntry::Position {
+            position: self.position,
+        });
+    }
+
+    /// Whether the EBB's step counters can be trusted to correct the tracked position.
+    pub fn is_homed(&self) -> bool {
+        self.homed
+    }
+
+    /// Notes that the EBB's step counters may have been reset, e.g. because it was unplugged, so
+    /// they no longer agree with the tracked position.
+    pub fn reset_counters(&mut self) {
+        self.homed = false;
+    }
+
+    /// Puts the in-flight command back, like `retry`, but as it may already have run, the
+    /// position is unknown until the queue is recovered.
+    pub fn lose_position(&mut self) {
+        self.retry();
+        self.homed = false;
+        self.position_lost = true;
+    }
+
+    pub fn is_position_lost(&self) -> bool {
+        self.position_lost
+    }
+
+    /// Replaces the tracked step position with the EBB's own count, in case a command did not
+    /// move the motors as modelled. Returns false if they already agreed, or if the counters are
+    /// not yet known to agree with the tracked position.
+    pub fn reconcile(&mut self, motor1: i32, motor2: i32) -> bool {
+        if !self.homed || (motor1, motor2) == (self.position.motor1, self.position.motor2) {
+            return false;
+        }
+
+        self.position = Position {
+            motor1,
+            motor2,
+            ..self.position
+        };
+        self.record(JournalEntry::Position {
+            position: self.position,
+        });
+
+        true
+    }
+
+    /// Returns the plotter to where it was after the last acknowledged command, e.g. after it
+    /// lost power mid-plot: raises the pen, homes, moves back with the pen up and lowers the pen
+    /// again if it was down. The queue then carries on from the next command.
+    ///
+    /// The EBB counts steps from where its motors were enabled, so after a power cycle homing
+    /// goes nowhere and the move back is measured from wherever the carriage stopped. The
+    /// carriage has to be moved back to home by hand first.
+    pub fn recover(&mut self) {
+        let position = self.position;
+        self.position_lost = false;
+
+        self.raise_pen();
+        self.interjections.push_back(EbbCommand::HomeMove {
+            step_frequency: RECOVERY_STEP_FREQUENCY,
+            position: None,
+        });
+        self.interjections.push_back(EbbCommand::HomeMove {
+            step_frequency: RECOVERY_STEP_FREQUENCY,
+            position: Some((position.motor1, position.motor2)),
+        });
+        if position.pen == PenState::Down {
+            self.interjections.push_back(pen_command(PenState::Down));
+        }
+    }
+
+    pub fn retire_finished(&mut self) {
+        self.jobs.retain(|job| !job.is_finished());
+    }
+
+    /// Removes a job. A job that has started drawing may have left the pen down, so it is
+    /// raised before the next job begins.
+    pub fn cancel(&mut self, id: u64) -> Result<Job, JobError> {
+        let index = self.index_of(id)?;
+        let job = self.jobs.remove(index).unwrap();
+        self.record(JournalEntry::Cancel { id });
+
+        if job.open {
+            self.cancelled.insert(id);
+        }
+
+        if job.is_started() {
+            self.raise_pen();
+        }
+
+        Ok(job)
+    }
+
+    /// Moves a job `offset` places towards the back of the queue, or towards the front if
+    /// negative. Jobs cannot overtake or be moved from behind a job that has started.
+    pub fn move_job(&mut self, id: u64, offset: i64) -> Result<(), JobError> {
+        let index = self.index_of(id)?;
+        let front_started = self.jobs.front().is_some_and(Job::is_started);
+
+        if index == 0 && front_started {
+            return Err(JobError::Started(id));
+        }
+
+        let first_movable = usize::from(front_started) as i64;
+        let last = self.jobs.len() as i64 - 1;
+        let target = (index as i64)
+            .saturating_add(offset)
+            .clamp(first_movable, last) as usize;
+
+        let job = self.jobs.remove(index).unwrap();
+        self.jobs.insert(target, job);
+        self.record(JournalEntry::Move { id, offset });
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        if self.jobs.front().is_some_and(Job::is_started) {
+            self.raise_pen();
+        }
+
+        self.jobs.clear();
+        self.record(JournalEntry::Clear);
+    }
+
+    /// Takes the command that lifts the pen off the paper while paused, if a job left it down.
+    pub fn park(&mut self) -> Option<EbbCommand> {
+        if self.parked || self.position.pen == PenState::Up {
+            return None;
+        }
+
+        self.parked = true;
+
+        Some(pen_command(PenState::Up))
+    }
+
+    /// Lowers the pen raised by `park` before the queue carries on.
+    pub fn unpark(&mut self) {
+        if self.parked {
+            self.parked = false;
+            self.interjections.push_back(pen_command(PenState::Down));
+        }
+    }
+
+    pub fn is_parked(&self) -> bool {
+        self.parked
+    }
+
+    fn raise_pen(&mut self) {
+        // The pen is meant to stay up now, so must not be lowered on resume.
+        self.parked = false;
+        self.interjections.push_back(pen_command(PenState::Up));
+    }
+
+    fn index_of(&self, id: u64) -> Result<usize, JobError> {
+        self.jobs
+            .iter()
+            .position(|job| job.id == id)
+            .ok_or(JobError::NotFound(id))
+    }
+
+    /// Number of commands still to run across all jobs.
+    pub fn command_count(&self) -> usize {
+        self.interjections.len()
+            + self
+                .jobs
+                .iter()
+                .map(|job| job.commands.len())
+                .sum::<usize>()
+    }
+
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    /// Every command still to run, including the one in flight, in the order they will run from
+    /// `position`.
+    pub fn pending(&self) -> impl Iterator<Item = &EbbCommand> {
+        self.in_flight
+            .iter()
+            .map(|(_, command, _)| command)
+            .chain(self.interjections.iter())
+            .chain(self.jobs.iter().flat_map(|job| job.commands.iter()))
+    }
+}
+
+/// Whether `command` sets the EBB's step counters outright rather than moving them along.
+fn sets_step_counters(command: &EbbCommand) -> bool {
+    matches!(
+        command,
+        EbbCommand::HomeMove { .. }
+            | EbbCommand::ClearStepPosition
+            | EbbCommand::EnableMotors { .. }
+    )
+}
+
+fn pen_command(state: PenState) -> EbbCommand {
+    EbbCommand::SetPen {
+        state,
+        duration: None,
+        port_b_pin: None,
+    }
+}
+
+pub fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,105 @@
+use crate::position::Position;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+};
+
+/// A single change to the job queue.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Open {
+        id: u64,
+        name: String,
+        client: String,
+        submitted_at_ms: u64,
+        /// Non-zero only when a partly run job is carried over from a previous journal.
+        #[serde(default)]
+        executed_commands: u64,
+    },
+    Push {
+        id: u64,
+        command: String,
+    },
+    Close {
+        id: u64,
+    },
+    /// A command of the job has run, leaving the plotter at `position`. Commands the EBB
+    /// rejected count as run too, as they are not retried.
+    Execute {
+        id: u64,
+        #[serde(default)]
+        position: Position,
+    },
+    /// The plotter moved outside of any job, e.g. while recovering.
+    Position {
+        position: Position,
+    },
+    Cancel {
+        id: u64,
+    },
+    Move {
+        id: u64,
+        offset: i64,
+    },
+    Clear,
+}
+
+/// Append-only log of changes to the job queue, one JSON entry per line, from which the queue
+/// can be rebuilt after a restart.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Reads every entry in the journal at `path`. A missing journal has no entries, and
+    /// unreadable lines (e.g. one torn by a crash mid-write) are skipped.
+    pub fn read(path: &Path) -> io::Result<Vec<JournalEntry>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => println!("Skipping unreadable journal entry '{}': {}", line, e),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Replaces the journal at `path` with `entries` and opens it for appending.
+    pub fn create(path: &Path, entries: &[JournalEntry]) -> io::Result<Journal> {
+        let temporary_path = path.with_extension("tmp");
+        let mut file = File::create(&temporary_path)?;
+        for entry in entries {
+            file.write_all(Self::line(entry).as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        Ok(Journal { file })
+    }
+
+    /// Failures are reported but not fatal, since stopping the plot would be worse than losing
+    /// the ability to resume it.
+    pub fn append(&mut self, entry: &JournalEntry) {
+        if let Err(e) = self.file.write_all(Self::line(entry).as_bytes()) {
+            println!("Failed to write to journal: {}", e);
+        }
+    }
+
+    fn line(entry: &JournalEntry) -> String {
+        // Written in one call so a crash cannot interleave partial entries.
+        format!("{}\n", serde_json::to_string(entry).unwrap())
+    }
+}
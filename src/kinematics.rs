@@ -0,0 +1,81 @@
+use crate::{ebb::EbbCommand, position::Position};
+use std::time::Duration;
+
+/// The EBB's step timer rate, used by `LM` and `LT`.
+pub const TICKS_PER_SECOND: f64 = 25_000.0;
+
+/// `LM` and `LT` rates count in fractions of a step, a whole step being this many.
+pub const RATE_PER_STEP: f64 = 2_147_483_648.0;
+
+/// How long the EBB spends carrying out `command` from `from`, including any delay it adds after
+/// moving the pen. Commands outside the motion queue take no time.
+pub fn duration(from: Position, command: &EbbCommand) -> Duration {
+    match command {
+        EbbCommand::StepperMove { duration, .. } | EbbCommand::MixedAxisMove { duration, .. } => {
+            Duration::from_millis(u64::from(*duration))
+        }
+        EbbCommand::LowLevelMove {
+            rate1,
+            steps1,
+            accel1,
+            rate2,
+            steps2,
+            accel2,
+            ..
+        } => {
+            let ticks = ticks_to_move(*rate1, *steps1, *accel1)
+                .max(ticks_to_move(*rate2, *steps2, *accel2));
+            Duration::from_secs_f64(ticks / TICKS_PER_SECOND)
+        }
+        EbbCommand::LowLevelMoveTimed { intervals, .. } => {
+            Duration::from_secs_f64(f64::from(*intervals) / TICKS_PER_SECOND)
+        }
+        // The motor with further to go steps at the given frequency.
+        EbbCommand::HomeMove { step_frequency, .. } => {
+            let to = from.after(command);
+            let longest = to
+                .motor1
+                .abs_diff(from.motor1)
+                .max(to.motor2.abs_diff(from.motor2));
+            Duration::from_secs_f64(f64::from(longest) / f64::from((*step_frequency).max(1)))
+        }
+        EbbCommand::SetPen { duration, .. } | EbbCommand::TogglePen { duration } => {
+            Duration::from_millis(u64::from(duration.unwrap_or(0)))
+        }
+        _ => Duration::ZERO,
+    }
+}
+
+/// Timer ticks an `LM` axis takes to cover `steps`, starting at `rate` and changing by `accel`
+/// each tick.
+pub fn ticks_to_move(rate: u32, steps: i32, accel: i32) -> f64 {
+    let distance = f64::from(steps.unsigned_abs()) * RATE_PER_STEP;
+    let rate = f64::from(rate);
+    let accel = f64::from(accel);
+
+    if distance == 0.0 {
+        0.0
+    } else if accel == 0.0 {
+        if rate > 0.0 {
+            distance / rate
+        } else {
+            0.0
+        }
+    } else {
+        // Solves rate * n + accel * n^2 / 2 = distance for n.
+        let discriminant = rate * rate + 2.0 * accel * distance;
+        if discriminant < 0.0 {
+            0.0
+        } else {
+            ((discriminant.sqrt() - rate) / accel).max(0.0)
+        }
+    }
+}
+
+/// Steps an `LT` axis takes in `ticks`, starting at `rate` and changing by `accel` each tick.
+pub fn steps_in_ticks(ticks: u32, rate: i32, accel: i32) -> i32 {
+    let ticks = f64::from(ticks);
+    let distance = f64::from(rate) * ticks + f64::from(accel) * ticks * (ticks + 1.0) / 2.0;
+
+    (distance / RATE_PER_STEP) as i32
+}
@@ -0,0 +1,93 @@
+use crate::{
+    ebb::EbbCommand,
+    position::{Position, Scale},
+};
+use clap::ValueEnum;
+
+/// AxiDraw models, which differ in how far the carriage can travel from home.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Model {
+    /// AxiDraw V2, V3 or SE/A4.
+    V3,
+    /// AxiDraw V3/A3 or SE/A3.
+    A3,
+    /// AxiDraw V3 XLX.
+    Xlx,
+    /// AxiDraw MiniKit.
+    MiniKit,
+    /// AxiDraw SE/A2.
+    A2,
+    /// AxiDraw SE/A1.
+    A1,
+    /// AxiDraw V3/B6.
+    B6,
+}
+
+impl Model {
+    pub fn travel_limits(self) -> TravelLimits {
+        let (width_mm, height_mm) = match self {
+            Model::V3 => (300.0, 218.0),
+            Model::A3 => (430.0, 297.0),
+            Model::Xlx => (595.0, 218.0),
+            Model::MiniKit => (160.0, 101.6),
+            Model::A2 => (594.0, 432.0),
+            Model::A1 => (864.0, 594.0),
+            Model::B6 => (190.0, 140.0),
+        };
+
+        TravelLimits {
+            width_mm,
+            height_mm,
+        }
+    }
+}
+
+/// The rectangle the carriage may move within, from home at the top left corner.
+#[derive(Clone, Copy, Debug)]
+pub struct TravelLimits {
+    pub width_mm: f64,
+    pub height_mm: f64,
+}
+
+impl TravelLimits {
+    /// Checks that running `command` from `from` keeps the carriage within the limits the whole
+    /// way. Most moves are straight lines, so only where they end needs checking. Low-level moves
+    /// can accelerate each motor differently and curve, but each motor only turns one way, so
+    /// they stay within the corners reached by running either motor's steps first.
+    pub fn check(&self, scale: &Scale, from: Position, command: &EbbCommand) -> Result<(), String> {
+        let to = from.after(command);
+        if to.motor1 == from.motor1 && to.motor2 == from.motor2 {
+            return Ok(());
+        }
+
+        let mut reached = vec![to];
+        if matches!(
+            command,
+            EbbCommand::LowLevelMove { .. } | EbbCommand::LowLevelMoveTimed { .. }
+        ) {
+            reached.push(Position {
+                motor1: to.motor1,
+                ..from
+            });
+            reached.push(Position {
+                motor2: to.motor2,
+                ..from
+            });
+        }
+
+        let Some(outside) = reached
+            .into_iter()
+            .map(|position| scale.in_mm(position))
+            .find(|mm| {
+                !(0.0..=self.width_mm).contains(&mm.x) || !(0.0..=self.height_mm).contains(&mm.y)
+            })
+        else {
+            return Ok(());
+        };
+
+        Err(format!(
+            "Move through {:.1},{:.1} mm would leave the {}x{} mm travel limits",
+            outside.x, outside.y, self.width_mm, self.height_mm
+        ))
+    }
+}
@@ -2,39 +2,541 @@ use axidraw_over_http::{
     axidraw_over_http_server::{AxidrawOverHttp, AxidrawOverHttpServer},
     BufferState, Command, Empty, RunningStatus,
 };
+use axidraw_over_tcp::{
+    axidraw_over_tcp_server::{AxidrawOverTcp, AxidrawOverTcpServer},
+    query_reply::Reply,
+    Bounds, CarriagePosition, CommandError, CurrentSense, DryRunReport, EmergencyStopReply,
+    EmergencyStopRequest, HomeDistance, ImageFormat, InvalidCommand, JobId, JobInfo, JobList,
+    JobRequest, MotorEnables, MotorStatus, MoveJobRequest, PlotterState, PreviewReply,
+    PreviewRequest, Progress, QueryReply, ReturnHomeRequest, SetOriginRequest, StepPosition,
+    SvgRequest,
+};
 use clap::Parser;
-use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use dry_run::DryRun;
+use ebb::{EbbCommand, EbbError, EbbResponse, PenState};
+use job::{Job, JobError, JobQueue, PushError};
+use limits::{Model, TravelLimits};
+use position::{Point, Position, Scale};
+use preview::Preview;
+use serial::{EmergencyStopper, Serial, SerialPortTransport, Transport};
+use simulator::Simulator;
 use std::{
-    collections::VecDeque,
-    io::{prelude::*, BufRead, BufReader, BufWriter},
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
     sync::Arc,
-    thread::{sleep, spawn},
+    thread::{spawn, JoinHandle},
     time::Duration,
 };
-use tokio::{
-    join,
-    sync::{
-        mpsc::{unbounded_channel, UnboundedSender},
-        Mutex,
-    },
+use svg::{Drawing, PaperSize};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot, watch, Mutex,
 };
-use tokio_stream::StreamExt;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{transport::Server, Request, Response, Status};
 
 mod axidraw_over_http {
     tonic::include_proto!("axidraw_over_http");
 }
+mod axidraw_over_tcp {
+    tonic::include_proto!("axidraw_over_tcp");
+}
+mod dry_run;
+mod ebb;
+mod http;
+mod job;
+mod journal;
+mod kinematics;
+mod limits;
+mod position;
+mod preview;
+mod serial;
+mod simulator;
+mod svg;
+mod tcp;
+#[cfg(test)]
+mod tests;
 
 enum ControlMessage {
     CheckBuffer,
+    Query(EbbCommand, oneshot::Sender<Result<EbbResponse, EbbError>>),
+    /// Collect the reply to an `ES` sent by the `EmergencyStopper`, then raise the pen and
+    /// correct the tracked position.
+    EmergencyStop(oneshot::Sender<Result<EbbResponse, EbbError>>),
+    /// Raise the pen and run the given `HM`, then correct the tracked position to where it goes.
+    ReturnHome(EbbCommand, oneshot::Sender<Result<EbbResponse, EbbError>>),
+    /// Check the tracked position against the EBB's step counters, if the motors are idle.
+    Reconcile,
+    /// Stop once the queue has run dry or been paused, leaving the plotter safe to walk away from.
+    Shutdown,
 }
 
+const SHUTTING_DOWN: &str = "Server is shutting down";
+
+const POSITION_LOST: &str =
+    "The EBB stopped answering mid-command, so the position is unknown; move the carriage home \
+     by hand and recover";
+
+/// How often the tracked position is checked against the EBB's step counters while idle.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Steps per second of the motor with further to go when returning home, unless asked otherwise.
+const HOME_STEP_FREQUENCY: u16 = 2000;
+
+#[derive(Clone)]
 struct AxidrawService {
     control_message_sender: UnboundedSender<ControlMessage>,
-    command_buffer: Arc<Mutex<VecDeque<String>>>,
+    state_change_sender: broadcast::Sender<()>,
+    command_buffer: Arc<Mutex<JobQueue>>,
     running_status: Arc<Mutex<RunningStatus>>,
+    last_command: Arc<Mutex<Option<EbbCommand>>>,
+    last_error: Arc<Mutex<Option<CommandError>>>,
+    shutdown_receiver: watch::Receiver<bool>,
+    emergency_stopper: EmergencyStopper,
+    scale: Scale,
+    limits: Option<TravelLimits>,
+    /// Where `ReturnHome` goes back to, from home.
+    origin: Arc<Mutex<Position>>,
+    /// Paper that SVG drawings are scaled to fit, unless a request gives its own.
+    paper: PaperSize,
+}
+
+impl AxidrawService {
+    async fn open_job(&self, name: Option<String>, client: String) -> u64 {
+        let job_id = self.command_buffer.lock().await.open(name, client);
+        self.notify_state_change();
+
+        job_id
+    }
+
+    async fn enqueue(&self, job_id: u64, command: EbbCommand) -> Result<(), PushError> {
+        self.command_buffer.lock().await.push(job_id, command)?;
+        self.check_buffer().await;
+
+        Ok(())
+    }
+
+    async fn close_job(&self, job_id: u64) {
+        self.command_buffer.lock().await.close(job_id);
+
+        // Wake the consumer in case the job was holding up the ones behind it.
+        self.check_buffer().await;
+        self.notify_state_change();
+    }
+
+    /// Queues a complete job in one go, so no other job can slip in while it is being added.
+    async fn queue_job(
+        &self,
+        name: Option<String>,
+        client: String,
+        commands: Vec<EbbCommand>,
+    ) -> JobInfo {
+        let mut buffer = self.command_buffer.lock().await;
+        let job_id = buffer.open(name, client);
+        for command in commands {
+            // Callers refuse jobs once shutting down, which is the only way this can fail.
+            let _ = buffer.push(job_id, command);
+        }
+        let job = buffer
+            .jobs()
+            .find(|job| job.id == job_id)
+            .map(job_info)
+            .unwrap();
+        buffer.close(job_id);
+        drop(buffer);
+
+        self.check_buffer().await;
+        self.notify_state_change();
+
+        JobInfo { open: false, ..job }
+    }
+
+    async fn remove_job(&self, job_id: u64) -> Result<(), JobError> {
+        self.command_buffer.lock().await.cancel(job_id)?;
+
+        // Wake the consumer in case the pen needs raising.
+        self.check_buffer().await;
+        self.notify_state_change();
+
+        Ok(())
+    }
+
+    async fn reorder_job(&self, job_id: u64, offset: i64) -> Result<(), JobError> {
+        self.command_buffer.lock().await.move_job(job_id, offset)?;
+
+        // Wake the consumer in case a job that is ready now runs first.
+        self.check_buffer().await;
+        self.notify_state_change();
+
+        Ok(())
+    }
+
+    async fn jobs(&self) -> Vec<JobInfo> {
+        self.command_buffer
+            .lock()
+            .await
+            .jobs()
+            .map(job_info)
+            .collect()
+    }
+
+    async fn check_buffer(&self) {
+        if *self.running_status.lock().await == RunningStatus::Running {
+            // The consumer may already have stopped if the server is shutting down.
+            let _ = self
+                .control_message_sender
+                .send(ControlMessage::CheckBuffer);
+        }
+    }
+
+    async fn clear_buffer(&self) {
+        self.command_buffer.clone().lock_owned().await.clear();
+        self.check_buffer().await;
+        self.notify_state_change();
+    }
+
+    async fn recover(&self) {
+        self.command_buffer.lock().await.recover();
+
+        // Recovering finds the position again, so resuming cannot be refused.
+        let _ = self.resume_buffer().await;
+        self.check_buffer().await;
+    }
+
+    /// Stops the motors mid-move, without waiting for the command in flight, and leaves the queue
+    /// paused, or empty if `clear_queue` is set.
+    async fn emergency_stop(&self, clear_queue: bool) -> Result<EmergencyStopReply, String> {
+        // Freeze the queue first so nothing more is sent after the stop.
+        *self.running_status.lock().await = RunningStatus::Paused;
+        if clear_queue {
+            self.command_buffer.lock().await.clear();
+        }
+
+        self.emergency_stopper
+            .stop()
+            .map_err(|e| format!("Could not send emergency stop: {}", e))?;
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.control_message_sender
+            .send(ControlMessage::EmergencyStop(reply_sender))
+            .map_err(|_| SHUTTING_DOWN.to_string())?;
+        let response = reply_receiver
+            .await
+            .map_err(|_| "Plotter did not answer the emergency stop".to_string())?;
+        self.notify_state_change();
+        let response = response.map_err(|e| e.to_string())?;
+
+        let position = self.command_buffer.lock().await.position();
+        let mut reply = EmergencyStopReply {
+            position: Some(StepPosition {
+                motor1: position.motor1,
+                motor2: position.motor2,
+            }),
+            ..Default::default()
+        };
+        if let EbbResponse::Interrupted {
+            interrupted,
+            fifo_steps1,
+            fifo_steps2,
+            remaining_steps1,
+            remaining_steps2,
+        } = response
+        {
+            reply.interrupted = interrupted;
+            reply.fifo_steps1 = fifo_steps1;
+            reply.fifo_steps2 = fifo_steps2;
+            reply.remaining_steps1 = remaining_steps1;
+            reply.remaining_steps2 = remaining_steps2;
+        }
+
+        Ok(reply)
+    }
+
+    /// Raises the pen and moves to the user origin, or home if `ignore_origin` is set. The consumer
+    /// runs it between queued commands, even while the queue is paused.
+    async fn return_home(
+        &self,
+        step_frequency: u16,
+        ignore_origin: bool,
+    ) -> Result<HomeDistance, String> {
+        let origin = if ignore_origin {
+            Position::default()
+        } else {
+            *self.origin.lock().await
+        };
+        // HM without a position always means home.
+        let position = Some((origin.motor1, origin.motor2)).filter(|&position| position != (0, 0));
+        let home = EbbCommand::HomeMove {
+            step_frequency,
+            position,
+        };
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.control_message_sender
+            .send(ControlMessage::ReturnHome(home, reply_sender))
+            .map_err(|_| SHUTTING_DOWN.to_string())?;
+        let response = reply_receiver
+            .await
+            .map_err(|_| "Plotter did not answer the return home".to_string())?;
+        self.notify_state_change();
+        response.map_err(|e| e.to_string())?;
+
+        Ok(self.home_distance().await)
+    }
+
+    /// Converts an SVG document to commands drawing it on the paper at the user origin, and queues
+    /// them as a job. The paper is the size the server was started with unless both sides are
+    /// given.
+    async fn queue_svg(
+        &self,
+        name: Option<String>,
+        client: String,
+        svg: &str,
+        (width_mm, height_mm): (f64, f64),
+    ) -> Result<JobInfo, String> {
+        let paper = requested_paper(width_mm, height_mm)?.unwrap_or(self.paper);
+        let drawing = Drawing::parse(svg, paper)?;
+        let origin = *self.origin.lock().await;
+        let steps_per_mm = self.scale.steps_per_mm(&origin);
+
+        Ok(self
+            .queue_job(name, client, drawing.commands(origin, steps_per_mm))
+            .await)
+    }
+
+    /// Makes the current position the user origin, or home again if `reset` is set.
+    async fn set_origin(&self, reset: bool) -> HomeDistance {
+        let origin = if reset {
+            Position::default()
+        } else {
+            self.command_buffer.lock().await.position()
+        };
+        *self.origin.lock().await = origin;
+
+        self.home_distance().await
+    }
+
+    async fn home_distance(&self) -> HomeDistance {
+        let position = self.command_buffer.lock().await.position();
+        let origin = *self.origin.lock().await;
+        let (position_mm, origin_mm) = (self.scale.in_mm(position), self.scale.in_mm(origin));
+        let home_mm = self.scale.in_mm(Position::default());
+
+        HomeDistance {
+            position: Some(self.carriage_position(position)),
+            origin: Some(self.carriage_position(origin)),
+            distance_from_home_mm: position_mm.distance_to(home_mm),
+            distance_from_origin_mm: position_mm.distance_to(origin_mm),
+        }
+    }
+
+    async fn pause_buffer(&self) {
+        *self.running_status.clone().lock_owned().await = RunningStatus::Paused;
+
+        // Wake the consumer so it raises the pen once the command in flight has finished. It
+        // may already have stopped if the server is shutting down.
+        let _ = self
+            .control_message_sender
+            .send(ControlMessage::CheckBuffer);
+        self.notify_state_change();
+    }
+
+    /// Carries on running the queue, unless the position has been lost and it needs recovering.
+    async fn resume_buffer(&self) -> Result<(), String> {
+        let mut running_status = self.running_status.clone().lock_owned().await;
+
+        if *running_status == RunningStatus::Paused {
+            let mut buffer = self.command_buffer.lock().await;
+            if buffer.is_position_lost() {
+                return Err(POSITION_LOST.to_string());
+            }
+            buffer.unpark();
+            drop(buffer);
+
+            *running_status = RunningStatus::Running;
+            *self.last_error.lock().await = None;
+            // The consumer may already have stopped if the server is shutting down.
+            let _ = self
+                .control_message_sender
+                .send(ControlMessage::CheckBuffer);
+            self.notify_state_change();
+        }
+
+        Ok(())
+    }
+
+    /// Traces what `commands` would draw from home, or what the queue will draw from the current
+    /// position if there are none.
+    async fn preview(&self, commands: Vec<EbbCommand>) -> Preview {
+        if !commands.is_empty() {
+            return Preview::trace(Position::default(), &commands, &self.scale);
+        }
+
+        let buffer = self.command_buffer.lock().await;
+        Preview::trace(buffer.position(), buffer.pending(), &self.scale)
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown_receiver.borrow()
+    }
+
+    /// Resolves once shutdown has begun.
+    async fn shutdown_started(&self) {
+        let _ = self
+            .shutdown_receiver
+            .clone()
+            .wait_for(|started| *started)
+            .await;
+    }
+
+    fn notify_state_change(&self) {
+        // Fails only when nobody is watching.
+        let _ = self.state_change_sender.send(());
+    }
+
+    fn carriage_position(&self, position: Position) -> CarriagePosition {
+        let steps = Point::from(position);
+        let mm = self.scale.in_mm(position);
+
+        CarriagePosition {
+            x_steps: steps.x,
+            y_steps: steps.y,
+            x_mm: mm.x,
+            y_mm: mm.y,
+            microstepping: position
+                .microstepping
+                .unwrap_or(self.scale.microstepping)
+                .into(),
+        }
+    }
+
+    async fn plotter_state(&self) -> PlotterState {
+        // Locked one at a time in the same order as the consumer, which holds the status while it
+        // takes the buffer.
+        let status = self.running_status.lock().await;
+        let buffer = self.command_buffer.lock().await;
+        let last_command = self.last_command.lock().await;
+        let last_error = self.last_error.lock().await;
+
+        let mut jobs = buffer.jobs().map(job_info);
+        let position = buffer.position();
+        let progress = buffer.progress();
+
+        PlotterState {
+            buffer_state: Some(BufferState {
+                buffer_length: buffer.command_count() as u64,
+                running_status: *status as i32,
+            }),
+            current_job: jobs.next(),
+            queued_jobs: jobs.collect(),
+            last_error: last_error.clone(),
+            last_command: last_command
+                .as_ref()
+                .map(|command| command.to_string())
+                .unwrap_or_default(),
+            position: Some(StepPosition {
+                motor1: position.motor1,
+                motor2: position.motor2,
+            }),
+            pen_down: position.pen == PenState::Down && !buffer.is_parked(),
+            carriage: Some(self.carriage_position(position)),
+            progress: Some(Progress {
+                executed_commands: progress.executed_commands,
+                elapsed_ms: progress.elapsed.as_millis() as u64,
+                remaining_ms: progress.remaining.as_millis() as u64,
+                percent_complete: progress.percent_complete(),
+            }),
+        }
+    }
+}
+
+fn job_info(job: &Job) -> JobInfo {
+    JobInfo {
+        id: job.id,
+        name: job.name.clone(),
+        total_commands: job.total_commands(),
+        executed_commands: job.executed_commands,
+        open: job.open,
+        client: job.client.clone(),
+        submitted_at_ms: job::millis_since_epoch(job.submitted_at),
+        elapsed_ms: job.elapsed.as_millis() as u64,
+        remaining_ms: job.remaining.as_millis() as u64,
+    }
+}
+
+fn dry_run_report(dry_run: &DryRun) -> DryRunReport {
+    let bounds = |min: Point, max: Point| Bounds {
+        min_x: min.x,
+        min_y: min.y,
+        max_x: max.x,
+        max_y: max.y,
+    };
+
+    DryRunReport {
+        duration_ms: dry_run.duration.as_millis() as u64,
+        pen_down_distance_steps: dry_run.pen_down_distance,
+        pen_down_distance_mm: dry_run.pen_down_distance_mm,
+        bounds_steps: Some(bounds(dry_run.min, dry_run.max)),
+        bounds_mm: Some(bounds(dry_run.min_mm, dry_run.max_mm)),
+        pen_lifts: dry_run.pen_lifts,
+        invalid_commands: dry_run
+            .invalid_commands
+            .iter()
+            .map(|invalid| InvalidCommand {
+                index: invalid.index as u64,
+                command: invalid.command.clone(),
+                error: invalid.error.clone(),
+            })
+            .collect(),
+    }
+}
+
+fn job_status(error: JobError) -> Status {
+    match error {
+        JobError::NotFound(_) => Status::not_found(error.to_string()),
+        JobError::Started(_) => Status::failed_precondition(error.to_string()),
+    }
+}
+
+/// Checks a paper size given with a request, which is left out if both sides are zero.
+fn requested_paper(width_mm: f64, height_mm: f64) -> Result<Option<PaperSize>, String> {
+    match (width_mm, height_mm) {
+        (0.0, 0.0) => Ok(None),
+        (width_mm, height_mm) if width_mm > 0.0 && height_mm > 0.0 => Ok(Some(PaperSize {
+            width_mm,
+            height_mm,
+        })),
+        _ => Err(format!(
+            "Paper width and height must both be positive, got {}x{} mm",
+            width_mm, height_mm
+        )),
+    }
+}
+
+/// Checks a requested homing speed, which defaults to `HOME_STEP_FREQUENCY` if zero.
+fn home_step_frequency(step_frequency: u32) -> Result<u16, String> {
+    match step_frequency {
+        0 => Ok(HOME_STEP_FREQUENCY),
+        2..=25_000 => Ok(step_frequency as u16),
+        _ => Err(format!(
+            "Step frequency must be between 2 and 25000, got {}",
+            step_frequency
+        )),
+    }
+}
+
+fn client_address(remote_addr: Option<SocketAddr>) -> String {
+    remote_addr
+        .map(|address| address.to_string())
+        .unwrap_or_default()
+}
+
+fn parse_command(contents: &str) -> Result<EbbCommand, String> {
+    contents
+        .parse::<EbbCommand>()
+        .map_err(|e| format!("Invalid command '{}': {}", contents, e))
 }
 
 #[tonic::async_trait]
@@ -43,66 +545,321 @@ impl AxidrawOverHttp for AxidrawService {
         &self,
         request: Request<tonic::Streaming<Command>>,
     ) -> Result<Response<Empty>, Status> {
+        let client = client_address(request.remote_addr());
+        let job_name = request
+            .metadata()
+            .get("job-name")
+            .and_then(|name| name.to_str().ok())
+            .map(String::from);
         let mut stream = request.into_inner();
 
-        while let Some(command) = stream.next().await {
-            let command = command?.contents;
+        if self.is_shutting_down() {
+            return Err(Status::unavailable(SHUTTING_DOWN));
+        }
 
-            if command.is_empty() || command.contains('\r') || command.contains('\n') {
-                return Err(Status::invalid_argument("Invalid command"));
-            }
+        let job_id = self.open_job(job_name, client).await;
 
-            self.command_buffer
-                .clone()
-                .lock_owned()
-                .await
-                .push_back(command);
+        let result = async {
+            while let Some(command) = stream.next().await {
+                let command =
+                    parse_command(&command?.contents).map_err(Status::invalid_argument)?;
 
-            if *self.running_status.lock().await == RunningStatus::Running {
-                self.control_message_sender
-                    .send(ControlMessage::CheckBuffer)
-                    .unwrap();
+                match self.enqueue(job_id, command).await {
+                    Ok(()) => {}
+                    Err(PushError::Closed) => return Err(Status::unavailable(SHUTTING_DOWN)),
+                    Err(error) => return Err(Status::aborted(error.to_string())),
+                }
             }
+
+            Ok(())
         }
+        .await;
 
-        Ok(Response::new(Empty {}))
+        // Commands buffered before a failure still run, as they did before jobs existed.
+        self.close_job(job_id).await;
+
+        result.map(|()| Response::new(Empty {}))
     }
 
     async fn clear(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        self.command_buffer.clone().lock_owned().await.clear();
+        self.clear_buffer().await;
 
         Ok(Response::new(Empty {}))
     }
 
     async fn pause(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        *self.running_status.clone().lock_owned().await = RunningStatus::Paused;
+        self.pause_buffer().await;
 
         Ok(Response::new(Empty {}))
     }
 
     async fn resume(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        let mut running_status = self.running_status.clone().lock_owned().await;
+        self.resume_buffer()
+            .await
+            .map_err(Status::failed_precondition)?;
 
-        if *running_status == RunningStatus::Paused {
-            *running_status = RunningStatus::Running;
-            self.control_message_sender
-                .send(ControlMessage::CheckBuffer)
-                .unwrap();
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn get_state(&self, _request: Request<Empty>) -> Result<Response<BufferState>, Status> {
+        let buffer_state = self.plotter_state().await.buffer_state.unwrap_or_default();
+
+        Ok(Response::new(buffer_state))
+    }
+}
+
+#[tonic::async_trait]
+impl AxidrawOverTcp for AxidrawService {
+    type WatchStateStream = ReceiverStream<Result<PlotterState, Status>>;
+
+    async fn get_status(&self, _request: Request<Empty>) -> Result<Response<PlotterState>, Status> {
+        Ok(Response::new(self.plotter_state().await))
+    }
+
+    async fn query(&self, request: Request<Command>) -> Result<Response<QueryReply>, Status> {
+        let command =
+            parse_command(&request.into_inner().contents).map_err(Status::invalid_argument)?;
+
+        if !command.is_query() {
+            return Err(Status::invalid_argument(format!(
+                "{} is not a query command",
+                command.name()
+            )));
         }
 
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.control_message_sender
+            .send(ControlMessage::Query(command, reply_sender))
+            .map_err(|_| Status::unavailable(SHUTTING_DOWN))?;
+
+        let response = reply_receiver
+            .await
+            .map_err(|_| Status::unavailable("Plotter did not answer the query"))?
+            .map_err(|e| match e {
+                EbbError::Disconnected(_)
+                | EbbError::Unanswered(_)
+                | EbbError::EmergencyStopped => Status::unavailable(e.to_string()),
+                e => Status::internal(e.to_string()),
+            })?;
+
+        Ok(Response::new(QueryReply {
+            reply: query_reply(response),
+        }))
+    }
+
+    async fn watch_state(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::WatchStateStream>, Status> {
+        let service = self.clone();
+        let mut state_changes = self.state_change_sender.subscribe();
+        let (state_sender, state_receiver) = mpsc::channel(16);
+
+        tokio::task::spawn(async move {
+            loop {
+                if state_sender
+                    .send(Ok(service.plotter_state().await))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+
+                // A lagging watcher only needs the latest state, which the next send provides.
+                if let Err(RecvError::Closed) = state_changes.recv().await {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(state_receiver)))
+    }
+
+    async fn submit_job(&self, request: Request<JobRequest>) -> Result<Response<JobInfo>, Status> {
+        let client = client_address(request.remote_addr());
+        let request = request.into_inner();
+        let commands = request
+            .commands
+            .iter()
+            .map(|contents| parse_command(contents))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Status::invalid_argument)?;
+        let name = Some(request.name).filter(|name| !name.is_empty());
+
+        if self.is_shutting_down() {
+            return Err(Status::unavailable(SHUTTING_DOWN));
+        }
+
+        Ok(Response::new(self.queue_job(name, client, commands).await))
+    }
+
+    async fn list_jobs(&self, _request: Request<Empty>) -> Result<Response<JobList>, Status> {
+        Ok(Response::new(JobList {
+            jobs: self.jobs().await,
+        }))
+    }
+
+    async fn cancel_job(&self, request: Request<JobId>) -> Result<Response<Empty>, Status> {
+        self.remove_job(request.into_inner().id)
+            .await
+            .map_err(job_status)?;
+
         Ok(Response::new(Empty {}))
     }
 
-    async fn get_state(&self, _request: Request<Empty>) -> Result<Response<BufferState>, Status> {
-        let (buffer, status) = join![self.command_buffer.lock(), self.running_status.lock()];
+    async fn move_job(&self, request: Request<MoveJobRequest>) -> Result<Response<Empty>, Status> {
+        let request = request.into_inner();
+        self.reorder_job(request.id, request.offset)
+            .await
+            .map_err(job_status)?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn emergency_stop(
+        &self,
+        request: Request<EmergencyStopRequest>,
+    ) -> Result<Response<EmergencyStopReply>, Status> {
+        let reply = AxidrawService::emergency_stop(self, request.into_inner().clear_queue)
+            .await
+            .map_err(Status::internal)?;
+
+        Ok(Response::new(reply))
+    }
 
-        return Ok(Response::new(BufferState {
-            buffer_length: buffer.len() as u64,
-            running_status: *status as i32,
-        }));
+    async fn recover(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        AxidrawService::recover(self).await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn dry_run(
+        &self,
+        request: Request<JobRequest>,
+    ) -> Result<Response<DryRunReport>, Status> {
+        let dry_run = DryRun::run(
+            &request.into_inner().commands,
+            &self.scale,
+            self.limits.as_ref(),
+        );
+
+        Ok(Response::new(dry_run_report(&dry_run)))
+    }
+
+    async fn preview(
+        &self,
+        request: Request<PreviewRequest>,
+    ) -> Result<Response<PreviewReply>, Status> {
+        let request = request.into_inner();
+        let format = ImageFormat::try_from(request.format)
+            .map_err(|_| Status::invalid_argument("Unknown image format"))?;
+        let commands = request
+            .commands
+            .iter()
+            .map(|contents| parse_command(contents))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Status::invalid_argument)?;
+
+        let (image, content_type) = AxidrawService::preview(self, commands).await.render(format);
+
+        Ok(Response::new(PreviewReply {
+            image,
+            content_type: content_type.to_string(),
+        }))
+    }
+
+    async fn return_home(
+        &self,
+        request: Request<ReturnHomeRequest>,
+    ) -> Result<Response<HomeDistance>, Status> {
+        let request = request.into_inner();
+        let step_frequency =
+            home_step_frequency(request.step_frequency).map_err(Status::invalid_argument)?;
+
+        let distance = AxidrawService::return_home(self, step_frequency, request.ignore_origin)
+            .await
+            .map_err(Status::internal)?;
+
+        Ok(Response::new(distance))
+    }
+
+    async fn set_origin(
+        &self,
+        request: Request<SetOriginRequest>,
+    ) -> Result<Response<HomeDistance>, Status> {
+        let distance = AxidrawService::set_origin(self, request.into_inner().reset).await;
+
+        Ok(Response::new(distance))
+    }
+
+    async fn get_home_distance(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<HomeDistance>, Status> {
+        Ok(Response::new(self.home_distance().await))
+    }
+
+    async fn submit_svg(&self, request: Request<SvgRequest>) -> Result<Response<JobInfo>, Status> {
+        let client = client_address(request.remote_addr());
+        let request = request.into_inner();
+        let name = Some(request.name).filter(|name| !name.is_empty());
+
+        if self.is_shutting_down() {
+            return Err(Status::unavailable(SHUTTING_DOWN));
+        }
+
+        let job = self
+            .queue_svg(
+                name,
+                client,
+                &request.svg,
+                (request.paper_width_mm, request.paper_height_mm),
+            )
+            .await
+            .map_err(Status::invalid_argument)?;
+
+        Ok(Response::new(job))
     }
 }
 
+fn query_reply(response: EbbResponse) -> Option<Reply> {
+    let reply = match response {
+        EbbResponse::Button { pressed } => Reply::ButtonPressed(pressed),
+        EbbResponse::Current { current, voltage } => Reply::Current(CurrentSense {
+            current: current.into(),
+            voltage: voltage.into(),
+        }),
+        EbbResponse::MotorEnables { motor1, motor2 } => Reply::MotorEnables(MotorEnables {
+            motor1: motor1.into(),
+            motor2: motor2.into(),
+        }),
+        EbbResponse::General(general) => Reply::General(general.into()),
+        EbbResponse::Layer(layer) => Reply::Layer(layer.into()),
+        EbbResponse::Motors {
+            command_executing,
+            motor1_moving,
+            motor2_moving,
+            fifo_pending,
+        } => Reply::Motors(MotorStatus {
+            command_executing,
+            motor1_moving,
+            motor2_moving,
+            fifo_pending,
+        }),
+        EbbResponse::NodeCount(count) => Reply::NodeCount(count),
+        EbbResponse::Pen(state) => Reply::PenUp(state == PenState::Up),
+        EbbResponse::ServoPower(powered) => Reply::ServoPowered(powered),
+        EbbResponse::StepPosition { motor1, motor2 } => {
+            Reply::StepPosition(StepPosition { motor1, motor2 })
+        }
+        EbbResponse::Nickname(nickname) => Reply::Nickname(nickname),
+        EbbResponse::Version(version) => Reply::Version(version),
+        EbbResponse::Ok | EbbResponse::Interrupted { .. } | EbbResponse::Lines(_) => return None,
+    };
+
+    Some(reply)
+}
+
 #[derive(Parser)]
 #[command(long_about = None)]
 struct Cli {
@@ -112,6 +869,43 @@ struct Cli {
     /// Serial device where the AxiDraw is connected. If none specified, will auto-detect.
     #[arg(short, long)]
     device: Option<String>,
+    /// Port to accept plain-text EBB commands on, one per line. Disabled if none specified.
+    #[arg(long)]
+    tcp_port: Option<u16>,
+    /// Port to serve the JSON HTTP API on. Disabled if none specified.
+    #[arg(long)]
+    http_port: Option<u16>,
+    /// File to journal the job queue to, so it survives restarts. A queue restored from the
+    /// journal starts paused. Disabled if none specified.
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// On SIGINT or SIGTERM, finish the queued jobs before exiting instead of abandoning them.
+    /// A second signal abandons whatever is left.
+    #[arg(long)]
+    drain_on_shutdown: bool,
+    /// Drive a simulated EBB instead of a real one, to try out clients without an AxiDraw.
+    #[arg(long)]
+    simulate: bool,
+    /// Motor steps per millimetre along X or Y at the --microstepping resolution.
+    #[arg(long, default_value_t = Scale::default().steps_per_mm)]
+    steps_per_mm: f64,
+    /// Microstep resolution the motors run at until an EM command sets one: 16, 8, 4, 2 or 1.
+    #[arg(long, default_value_t = Scale::default().microstepping, value_parser = parse_microstepping)]
+    microstepping: u8,
+    /// AxiDraw model, to refuse moves beyond its travel. Moves are not limited if none specified.
+    #[arg(long, value_enum)]
+    model: Option<Model>,
+    /// Paper that uploaded SVG drawings are scaled to fit, as WIDTHxHEIGHT in mm. Defaults to the
+    /// travel of --model, or A4 landscape if none specified.
+    #[arg(long)]
+    paper_size: Option<PaperSize>,
+}
+
+fn parse_microstepping(value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(microstepping @ (1 | 2 | 4 | 8 | 16)) => Ok(microstepping),
+        _ => Err("must be 16, 8, 4, 2 or 1".to_string()),
+    }
 }
 
 #[tokio::main]
@@ -119,109 +913,551 @@ async fn main() {
     let cli = Cli::parse();
     let port_number = cli.port.unwrap_or(7878);
 
-    println!("Waiting for serial connection...");
-    let serial_port = get_serial_port(&cli.device);
-    println!(
-        "Serial connection {} opened",
-        serial_port.name().unwrap_or("unknown".to_string())
-    );
+    let transport: Box<dyn Transport> = if cli.simulate {
+        Box::new(Simulator)
+    } else {
+        Box::new(SerialPortTransport {
+            device: cli.device.clone(),
+        })
+    };
+    let job_queue = match &cli.journal {
+        Some(path) => JobQueue::restore(path)
+            .unwrap_or_else(|e| panic!("Could not open journal {}: {}", path.display(), e)),
+        None => JobQueue::default(),
+    };
+    for job in job_queue.jobs() {
+        println!(
+            "Restored job {} '{}' from journal: {} of {} commands already executed",
+            job.id,
+            job.name,
+            job.executed_commands,
+            job.total_commands()
+        );
+    }
+
+    let running_status = initial_status(&job_queue);
+    if running_status == RunningStatus::Paused {
+        let position = job_queue.position();
+        println!(
+            "Plotter was last at step position {},{} with the pen {}; recover to carry on from \
+             there, after moving it to home by hand if the EBB lost power, or resume to carry on \
+             from wherever it is now",
+            position.motor1,
+            position.motor2,
+            if position.pen == PenState::Down {
+                "down"
+            } else {
+                "up"
+            }
+        );
+    }
+    let scale = Scale {
+        steps_per_mm: cli.steps_per_mm,
+        microstepping: cli.microstepping,
+    };
+    let limits = cli.model.map(Model::travel_limits);
+    let paper = cli.paper_size.unwrap_or_else(|| {
+        limits.map_or_else(PaperSize::default, |limits| PaperSize {
+            width_mm: limits.width_mm,
+            height_mm: limits.height_mm,
+        })
+    });
+    let (service, shutdown_sender, consumer_thread) =
+        start(transport, job_queue, running_status, scale, limits, paper);
+
+    if let Some(tcp_port) = cli.tcp_port {
+        tokio::task::spawn(tcp::serve(service.clone(), tcp_port));
+    }
+
+    if let Some(http_port) = cli.http_port {
+        tokio::task::spawn(http::serve(service.clone(), http_port));
+    }
+
+    let server_service = service.clone();
+    let server = Server::builder()
+        .add_service(AxidrawOverHttpServer::from_arc(service.clone()))
+        .add_service(AxidrawOverTcpServer::from_arc(service.clone()))
+        .serve_with_shutdown(
+            (IpAddr::from_str("::").unwrap(), port_number).into(),
+            async move { server_service.shutdown_started().await },
+        );
+    tokio::task::spawn(server);
+
+    shutdown_signal().await;
+    println!("Shutting down");
+
+    begin_shutdown(&service, &shutdown_sender, cli.drain_on_shutdown).await;
+
+    let mut consumer_thread = tokio::task::spawn_blocking(move || consumer_thread.join());
+    if cli.drain_on_shutdown {
+        let remaining = service.command_buffer.lock().await.command_count();
+        if *service.running_status.lock().await == RunningStatus::Running && remaining > 0 {
+            println!(
+                "Finishing {} queued commands; signal again to abandon them",
+                remaining
+            );
+        }
+
+        tokio::select! {
+            _ = &mut consumer_thread => return,
+            _ = shutdown_signal() => service.pause_buffer().await,
+        }
+    }
+    let _ = consumer_thread.await;
+}
+
+/// Refuses new work and tells the consumer thread to finish, which raises the pen and disables
+/// the motors once it stops. The queue is left paused for the journal, unless `drain` lets it run
+/// dry first.
+async fn begin_shutdown(
+    service: &AxidrawService,
+    shutdown_sender: &watch::Sender<bool>,
+    drain: bool,
+) {
+    let _ = shutdown_sender.send(true);
+    service.command_buffer.lock().await.close_all();
+    service.notify_state_change();
+    if !drain {
+        service.pause_buffer().await;
+    }
+    // The consumer thread may already have stopped, e.g. if it panicked.
+    let _ = service
+        .control_message_sender
+        .send(ControlMessage::Shutdown);
+}
+
+/// Starts a restored queue paused, so the user can check the plotter before it carries on.
+fn initial_status(job_queue: &JobQueue) -> RunningStatus {
+    if job_queue.command_count() > 0 {
+        RunningStatus::Paused
+    } else {
+        RunningStatus::Running
+    }
+}
+
+/// Connects to the EBB and starts the consumer thread that feeds it from `job_queue`.
+fn start(
+    transport: Box<dyn Transport>,
+    job_queue: JobQueue,
+    running_status: RunningStatus,
+    scale: Scale,
+    limits: Option<TravelLimits>,
+    paper: PaperSize,
+) -> (Arc<AxidrawService>, watch::Sender<bool>, JoinHandle<()>) {
+    let (mut serial, emergency_stopper) = Serial::connect(transport);
 
     let (control_message_sender, mut control_message_receiver) =
         unbounded_channel::<ControlMessage>();
-    let running_status = Arc::new(Mutex::new(RunningStatus::Running));
-    let command_buffer = Arc::new(Mutex::new(VecDeque::<String>::new()));
+    let running_status = Arc::new(Mutex::new(running_status));
+    let command_buffer = Arc::new(Mutex::new(job_queue));
+    let last_command = Arc::new(Mutex::new(None));
+    let last_error = Arc::new(Mutex::new(None));
+    let (state_change_sender, _) = broadcast::channel(16);
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
 
     let consumer_thread_running_status = running_status.clone();
     let consumer_thread_command_buffer = command_buffer.clone();
+    let consumer_thread_last_command = last_command.clone();
+    let consumer_thread_last_error = last_error.clone();
+    let consumer_thread_state_change_sender = state_change_sender.clone();
+
+    // Holds the sender weakly so the consumer still stops once the service is dropped.
+    let reconcile_sender = control_message_sender.downgrade();
+    tokio::task::spawn(async move {
+        let start = tokio::time::Instant::now() + RECONCILE_INTERVAL;
+        let mut interval = tokio::time::interval_at(start, RECONCILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(sender) = reconcile_sender.upgrade() else {
+                break;
+            };
+            if sender.send(ControlMessage::Reconcile).is_err() {
+                break;
+            }
+        }
+    });
+
+    let consumer_thread = spawn(move || {
+        let mut shutting_down = false;
+        let mut reconciled_at = None;
+
+        'consumer: while !shutting_down {
+            if !serial.is_connected()
+                && wait_for_reconnection(
+                    &mut serial,
+                    &consumer_thread_command_buffer,
+                    &mut control_message_receiver,
+                    &consumer_thread_running_status,
+                    &consumer_thread_last_error,
+                    &consumer_thread_state_change_sender,
+                )
+            {
+                break;
+            }
+
+            // Every sender is gone once the service itself is dropped.
+            let Some(message) = control_message_receiver.blocking_recv() else {
+                break;
+            };
+
+            match message {
+                ControlMessage::CheckBuffer => {}
+                ControlMessage::Query(command, reply_sender) => {
+                    let _ = reply_sender.send(serial.send(&command));
+                    continue;
+                }
+                ControlMessage::EmergencyStop(reply_sender) => {
+                    let _ = reply_sender.send(recover_from_emergency_stop(
+                        &mut serial,
+                        &consumer_thread_command_buffer,
+                    ));
+                    continue;
+                }
+                ControlMessage::ReturnHome(home, reply_sender) => {
+                    let _ = reply_sender.send(return_home(
+                        &mut serial,
+                        &consumer_thread_command_buffer,
+                        &home,
+                    ));
+                    continue;
+                }
+                // Reaching here means the queue has run dry or is paused, so the motors will
+                // soon be idle.
+                ControlMessage::Reconcile => {
+                    if reconcile_position(
+                        &mut serial,
+                        &consumer_thread_command_buffer,
+                        &mut reconciled_at,
+                    ) {
+                        let _ = consumer_thread_state_change_sender.send(());
+                    }
+                    continue;
+                }
+                ControlMessage::Shutdown => shutting_down = true,
+            }
 
-    spawn(move || loop {
-        let control_message = control_message_receiver.blocking_recv().unwrap();
+            loop {
+                if !serial.is_connected() {
+                    if wait_for_reconnection(
+                        &mut serial,
+                        &consumer_thread_command_buffer,
+                        &mut control_message_receiver,
+                        &consumer_thread_running_status,
+                        &consumer_thread_last_error,
+                        &consumer_thread_state_change_sender,
+                    ) {
+                        break 'consumer;
+                    }
+                    break;
+                }
+
+                // Queries jump the queue, running between buffered commands.
+                while let Ok(control_message) = control_message_receiver.try_recv() {
+                    match control_message {
+                        ControlMessage::CheckBuffer => {}
+                        ControlMessage::Query(command, reply_sender) => {
+                            let _ = reply_sender.send(serial.send(&command));
+                        }
+                        ControlMessage::EmergencyStop(reply_sender) => {
+                            let _ = reply_sender.send(recover_from_emergency_stop(
+                                &mut serial,
+                                &consumer_thread_command_buffer,
+                            ));
+                        }
+                        ControlMessage::ReturnHome(home, reply_sender) => {
+                            let _ = reply_sender.send(return_home(
+                                &mut serial,
+                                &consumer_thread_command_buffer,
+                                &home,
+                            ));
+                        }
+                        // The motors are kept busy while the queue runs.
+                        ControlMessage::Reconcile => {}
+                        ControlMessage::Shutdown => shutting_down = true,
+                    }
+                }
 
-        match control_message {
-            ControlMessage::CheckBuffer => loop {
                 let state = consumer_thread_running_status.blocking_lock();
                 let mut buffer = consumer_thread_command_buffer.clone().blocking_lock_owned();
 
-                if *state != RunningStatus::Running || buffer.is_empty() {
+                if *state != RunningStatus::Running {
+                    // Keep the pen from bleeding into the paper while paused.
+                    let pen_up = buffer.park();
+                    drop(buffer);
+                    drop(state);
+
+                    if let Some(command) = pen_up {
+                        if let Err(error) = serial.send(&command) {
+                            println!("Failed to raise the pen while paused: {}", error);
+                        }
+                        let _ = consumer_thread_state_change_sender.send(());
+                    }
+
                     break;
                 }
 
-                let command = buffer.pop_front().unwrap().clone();
+                let Some(command) = buffer.pop() else {
+                    break;
+                };
+                let refusal = limits
+                    .and_then(|limits| limits.check(&scale, buffer.position(), &command).err());
                 drop(buffer);
                 drop(state);
 
-                send_to_serial_and_wait_for_ok(&*serial_port, command.as_str());
-            },
+                // Left at the head of the queue, so resuming checks it again and only clearing or
+                // cancelling its job gets past it.
+                if let Some(error) = refusal {
+                    println!("Pausing after refusing {}: {}", command, error);
+
+                    *consumer_thread_running_status.blocking_lock() = RunningStatus::Paused;
+                    *consumer_thread_last_error.blocking_lock() = Some(CommandError {
+                        command: command.to_string(),
+                        error,
+                    });
+
+                    consumer_thread_command_buffer.blocking_lock().retry();
+                    let _ = consumer_thread_state_change_sender.send(());
+                    continue;
+                }
+
+                let result = serial.send(&command);
+                // Caught by an emergency stop before it was written, so it runs on resume. The
+                // stop has already paused the queue.
+                if let Err(EbbError::EmergencyStopped) = result {
+                    consumer_thread_command_buffer.blocking_lock().retry();
+                    continue;
+                }
+                if let Err(error) = &result {
+                    println!("Pausing after error from serial port: {}", error);
+
+                    *consumer_thread_running_status.blocking_lock() = RunningStatus::Paused;
+                    *consumer_thread_last_error.blocking_lock() = Some(CommandError {
+                        command: command.to_string(),
+                        error: error.to_string(),
+                    });
+                }
+
+                let mut buffer = consumer_thread_command_buffer.blocking_lock();
+                match result {
+                    // It never reached the EBB.
+                    Err(EbbError::Disconnected(_)) => buffer.retry(),
+                    Err(EbbError::Unanswered(_)) => buffer.lose_position(),
+                    result => buffer.finish(result.is_ok()),
+                }
+                buffer.retire_finished();
+                drop(buffer);
+                *consumer_thread_last_command.blocking_lock() = Some(command);
+                let _ = consumer_thread_state_change_sender.send(());
+            }
         }
+
+        // The tracked position keeps the pen state the job left, so a recovered plot lowers it
+        // again.
+        let pen_up = EbbCommand::SetPen {
+            state: PenState::Up,
+            duration: None,
+            port_b_pin: None,
+        };
+        let motors_off = EbbCommand::EnableMotors {
+            enable1: 0,
+            enable2: Some(0),
+        };
+        for command in [pen_up, motors_off] {
+            if !serial.is_connected() {
+                break;
+            }
+            if let Err(error) = serial.send(&command) {
+                println!("Failed to send {} while shutting down: {}", command, error);
+            }
+        }
+
+        // Dropping the reader closes the serial port.
     });
 
-    let service = AxidrawOverHttpServer::new(AxidrawService {
+    let service = Arc::new(AxidrawService {
         control_message_sender,
+        state_change_sender,
         running_status,
         command_buffer,
+        last_command,
+        last_error,
+        shutdown_receiver,
+        emergency_stopper,
+        scale,
+        limits,
+        origin: Arc::new(Mutex::new(Position::default())),
+        paper,
     });
 
-    let server = Server::builder().add_service(service).serve_with_shutdown(
-        (IpAddr::from_str("::").unwrap(), port_number).into(),
-        async move {
-            tokio::signal::ctrl_c().await.unwrap();
-        },
-    );
-
-    let _ = tokio::task::spawn(server).await;
+    (service, shutdown_sender, consumer_thread)
 }
 
-fn get_serial_port(device: &Option<String>) -> Box<dyn SerialPort> {
-    let port_filter = |port_info: &&SerialPortInfo| {
-        if let Some(device) = device {
-            port_info.port_name == *device
-        } else if let SerialPortType::UsbPort(usb_port_info) = &port_info.port_type {
-            usb_port_info
-                .product
-                .as_ref()
-                .unwrap_or(&"".to_string())
-                .contains("EiBotBoard")
-        } else {
-            false
+/// Collects the EBB's report of what `ES` abandoned, then raises the pen and asks the EBB where the
+/// motors actually stopped, since the aborted moves no longer match the acknowledged ones.
+fn recover_from_emergency_stop(
+    serial: &mut Serial,
+    command_buffer: &Mutex<JobQueue>,
+) -> Result<EbbResponse, EbbError> {
+    let response = serial.emergency_stop_reply()?;
+
+    serial.send(&EbbCommand::SetPen {
+        state: PenState::Up,
+        duration: None,
+        port_b_pin: None,
+    })?;
+
+    match serial.send(&EbbCommand::QueryStepPosition)? {
+        EbbResponse::StepPosition { motor1, motor2 } => {
+            command_buffer.blocking_lock().stopped_at(motor1, motor2);
         }
-    };
+        response => {
+            return Err(EbbError::UnexpectedResponse {
+                command: "QS",
+                response: format!("{:?}", response),
+            })
+        }
+    }
 
-    let port_info = loop {
-        let port_info = serialport::available_ports()
-            .unwrap_or_default()
-            .iter()
-            .find(port_filter)
-            .cloned();
+    Ok(response)
+}
 
-        if let Some(port_info) = port_info {
-            break port_info;
-        } else {
-            sleep(Duration::from_secs(1));
+/// Raises the pen and runs `home`, an `HM` the EBB starts once the moves before it have finished.
+fn return_home(
+    serial: &mut Serial,
+    command_buffer: &Mutex<JobQueue>,
+    home: &EbbCommand,
+) -> Result<EbbResponse, EbbError> {
+    serial.send(&EbbCommand::SetPen {
+        state: PenState::Up,
+        duration: None,
+        port_b_pin: None,
+    })?;
+    let response = serial.send(home)?;
+
+    let mut buffer = command_buffer.blocking_lock();
+    let position = buffer.position().after(home);
+    buffer.stopped_at(position.motor1, position.motor2);
+
+    Ok(response)
+}
+
+/// Checks the tracked position against the EBB's step counters once the motors have stopped,
+/// correcting it if they disagree. Skipped if nothing has moved since the last check, or until the
+/// plotter has been homed, since a restored position would be overwritten with counts from
+/// wherever it was at power on. Returns true if the position was corrected.
+fn reconcile_position(
+    serial: &mut Serial,
+    command_buffer: &Mutex<JobQueue>,
+    reconciled_at: &mut Option<Position>,
+) -> bool {
+    let buffer = command_buffer.blocking_lock();
+    let position = buffer.position();
+    if !buffer.is_homed() || *reconciled_at == Some(position) {
+        return false;
+    }
+    drop(buffer);
+
+    // Counters read mid-move would not match the position after the moves already queued.
+    match serial.send(&EbbCommand::QueryMotors) {
+        Ok(EbbResponse::Motors {
+            command_executing: false,
+            fifo_pending: false,
+            ..
+        }) => {}
+        Ok(_) => return false,
+        Err(error) => {
+            println!("Could not check whether the motors are idle: {}", error);
+            return false;
+        }
+    }
+
+    let (motor1, motor2) = match serial.send(&EbbCommand::QueryStepPosition) {
+        Ok(EbbResponse::StepPosition { motor1, motor2 }) => (motor1, motor2),
+        response => {
+            println!("Could not read the step position: {:?}", response);
+            return false;
         }
     };
 
-    serialport::new(&port_info.port_name, 9600)
-        .timeout(Duration::from_secs(1))
-        .open()
-        .unwrap_or_else(|_| panic!("Could not create port on {}", &port_info.port_name))
+    let mut buffer = command_buffer.blocking_lock();
+    let corrected = buffer.reconcile(motor1, motor2);
+    if corrected {
+        println!(
+            "Corrected tracked step position from {},{} to {},{}",
+            position.motor1, position.motor2, motor1, motor2
+        );
+    }
+    *reconciled_at = Some(buffer.position());
+
+    corrected
 }
 
-fn send_to_serial_and_wait_for_ok(serial_port: &dyn SerialPort, command: &str) {
-    println!("Writing to serial port: {}", command);
+/// Pauses the queue while the EBB is unplugged and waits for it to come back, answering anything
+/// that needs the plotter meanwhile with an error. Returns true if shutdown was requested first.
+fn wait_for_reconnection(
+    serial: &mut Serial,
+    command_buffer: &Mutex<JobQueue>,
+    control_message_receiver: &mut UnboundedReceiver<ControlMessage>,
+    running_status: &Mutex<RunningStatus>,
+    last_error: &Mutex<Option<CommandError>>,
+    state_change_sender: &broadcast::Sender<()>,
+) -> bool {
+    println!("Pausing until the EBB is reconnected");
 
-    let mut serial_reader_lines = BufReader::new(serial_port.try_clone().unwrap()).lines();
+    *running_status.blocking_lock() = RunningStatus::Paused;
+    last_error
+        .blocking_lock()
+        .get_or_insert_with(|| CommandError {
+            command: String::new(),
+            error: "serial port disconnected".to_string(),
+        });
+    let _ = state_change_sender.send(());
 
-    let mut serial_writer = BufWriter::new(serial_port.try_clone().unwrap());
-    serial_writer
-        .write_all(format!("{}\r", command).as_bytes())
-        .unwrap();
-    serial_writer.flush().unwrap();
+    let disconnected = || Err(EbbError::Disconnected("waiting to reconnect".to_string()));
+    let mut shutting_down = false;
+    let reconnected = serial.reconnect(|| {
+        while let Ok(control_message) = control_message_receiver.try_recv() {
+            match control_message {
+                ControlMessage::CheckBuffer | ControlMessage::Reconcile => {}
+                ControlMessage::Query(_, reply_sender) => {
+                    let _ = reply_sender.send(disconnected());
+                }
+                ControlMessage::EmergencyStop(reply_sender)
+                | ControlMessage::ReturnHome(_, reply_sender) => {
+                    let _ = reply_sender.send(disconnected());
+                }
+                ControlMessage::Shutdown => shutting_down = true,
+            }
+        }
 
-    let response = loop {
-        if let Ok(response) = serial_reader_lines.next().unwrap() {
-            break response;
+        shutting_down
+    });
+
+    if reconnected {
+        command_buffer.blocking_lock().reset_counters();
+        println!(
+            "EBB reconnected; if it lost power, move it to home by hand and recover, or resume to \
+             carry on"
+        );
+        let _ = state_change_sender.send(());
+    }
+
+    !reconnected
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
         }
-    };
+    }
 
-    println!("Repsonse from serial port: {}", &response);
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
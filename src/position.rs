@@ -0,0 +1,167 @@
+use crate::{
+    ebb::{self, EbbCommand, PenState},
+    kinematics::steps_in_ticks,
+};
+use serde::{Deserialize, Serialize};
+
+/// Where the plotter is, worked out from the commands it has acknowledged. Steps are counted
+/// from home, where the EBB's step counters were last cleared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub motor1: i32,
+    pub motor2: i32,
+    pub pen: PenState,
+    /// Microstep resolution set by the last `EM` command, if any.
+    #[serde(default)]
+    pub microstepping: Option<u8>,
+}
+
+impl Position {
+    /// The position once `command` has run.
+    pub fn after(self, command: &EbbCommand) -> Position {
+        match command {
+            EbbCommand::StepperMove { steps1, steps2, .. } => {
+                self.moved_by(*steps1, steps2.unwrap_or_default())
+            }
+            EbbCommand::LowLevelMove { steps1, steps2, .. } => self.moved_by(*steps1, *steps2),
+            EbbCommand::LowLevelMoveTimed {
+                intervals,
+                rate1,
+                accel1,
+                rate2,
+                accel2,
+                ..
+            } => self.moved_by(
+                steps_in_ticks(*intervals, *rate1, *accel1),
+                steps_in_ticks(*intervals, *rate2, *accel2),
+            ),
+            // Mixed axis moves step both motors for each axis, as on the AxiDraw's belts.
+            EbbCommand::MixedAxisMove {
+                steps_a, steps_b, ..
+            } => self.moved_by(steps_a + steps_b, steps_a - steps_b),
+            EbbCommand::HomeMove { position, .. } => {
+                let (motor1, motor2) = position.unwrap_or_default();
+                Position {
+                    motor1,
+                    motor2,
+                    ..self
+                }
+            }
+            EbbCommand::ClearStepPosition => Position {
+                motor1: 0,
+                motor2: 0,
+                ..self
+            },
+            // Setting the microstep resolution or disabling the motors also clears the step
+            // counters, since the old count no longer matches the motors.
+            EbbCommand::EnableMotors { enable1, .. } => Position {
+                motor1: 0,
+                motor2: 0,
+                microstepping: ebb::microstep_resolution(*enable1).or(self.microstepping),
+                ..self
+            },
+            EbbCommand::SetPen { state, .. } => Position {
+                pen: *state,
+                ..self
+            },
+            EbbCommand::TogglePen { .. } => Position {
+                pen: match self.pen {
+                    PenState::Up => PenState::Down,
+                    PenState::Down => PenState::Up,
+                },
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    fn moved_by(self, steps1: i32, steps2: i32) -> Position {
+        Position {
+            motor1: self.motor1.saturating_add(steps1),
+            motor2: self.motor2.saturating_add(steps2),
+            ..self
+        }
+    }
+}
+
+/// A point on the paper along the AxiDraw's X and Y axes, measured from home.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    pub fn distance_to(self, other: Point) -> f64 {
+        (other.x - self.x).hypot(other.y - self.y)
+    }
+
+    /// The nearer corner of the box containing both points.
+    pub fn min(self, other: Point) -> Point {
+        Point {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+        }
+    }
+
+    /// The further corner of the box containing both points.
+    pub fn max(self, other: Point) -> Point {
+        Point {
+            x: self.x.max(other.x),
+            y: self.y.max(other.y),
+        }
+    }
+}
+
+impl From<Position> for Point {
+    /// The two motors drive the carriage through a shared belt, so each axis moves by the sum or
+    /// difference of their steps.
+    fn from(position: Position) -> Self {
+        let motor1 = f64::from(position.motor1);
+        let motor2 = f64::from(position.motor2);
+
+        Point {
+            x: (motor1 + motor2) / 2.0,
+            y: (motor1 - motor2) / 2.0,
+        }
+    }
+}
+
+/// Converts steps to millimetres, allowing for the microstep resolution the motors are set to.
+#[derive(Clone, Copy, Debug)]
+pub struct Scale {
+    /// Steps per millimetre along X or Y at `microstepping`.
+    pub steps_per_mm: f64,
+    /// Microstep resolution assumed until an `EM` command sets one.
+    pub microstepping: u8,
+}
+
+impl Default for Scale {
+    /// The AxiDraw at the EBB's default 16x microstepping.
+    fn default() -> Self {
+        Scale {
+            steps_per_mm: 80.0,
+            microstepping: 16,
+        }
+    }
+}
+
+impl Scale {
+    /// Steps per millimetre at the microstep resolution `position` was reached at.
+    pub fn steps_per_mm(&self, position: &Position) -> f64 {
+        let microstepping = position.microstepping.unwrap_or(self.microstepping);
+
+        self.steps_per_mm * f64::from(microstepping) / f64::from(self.microstepping)
+    }
+
+    /// Where `position` is along the X and Y axes, in millimetres.
+    pub fn in_mm(&self, position: Position) -> Point {
+        let steps = Point::from(position);
+        let steps_per_mm = self.steps_per_mm(&position);
+
+        Point {
+            x: steps.x / steps_per_mm,
+            y: steps.y / steps_per_mm,
+        }
+    }
+}
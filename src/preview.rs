@@ -0,0 +1,190 @@
+use crate::{
+    axidraw_over_tcp::ImageFormat,
+    ebb::{EbbCommand, PenState},
+    position::{Point, Position, Scale},
+};
+use std::fmt::Write;
+
+/// Width of the line drawn for the pen.
+const PEN_WIDTH_MM: f64 = 0.5;
+
+/// Length in pixels of the longer side of a PNG preview.
+const PNG_SIZE: f64 = 1024.0;
+
+/// A straight line drawn with the pen down, in millimetres from home.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub from: Point,
+    pub to: Point,
+}
+
+/// What a sequence of commands draws, worked out without sending any of them.
+#[derive(Debug, Default)]
+pub struct Preview {
+    pub segments: Vec<Segment>,
+}
+
+impl Preview {
+    /// Follows `commands` from `start`, keeping each move made with the pen down.
+    pub fn trace<'a>(
+        start: Position,
+        commands: impl IntoIterator<Item = &'a EbbCommand>,
+        scale: &Scale,
+    ) -> Preview {
+        let mut position = start;
+        let mut segments = Vec::new();
+
+        for command in commands {
+            let next = position.after(command);
+
+            if position.pen == PenState::Down
+                && (next.motor1, next.motor2) != (position.motor1, position.motor2)
+            {
+                segments.push(Segment {
+                    from: scale.in_mm(position),
+                    to: scale.in_mm(next),
+                });
+            }
+
+            position = next;
+        }
+
+        Preview { segments }
+    }
+
+    /// The area to show: everything drawn, and home so the drawing can be placed on the paper.
+    /// Padded so the pen's width is not cut off at the edges.
+    fn bounds(&self) -> (Point, Point) {
+        let home = Point { x: 0.0, y: 0.0 };
+        let (min, max) = self
+            .segments
+            .iter()
+            .flat_map(|segment| [segment.from, segment.to])
+            .fold((home, home), |(min, max), point| {
+                (min.min(point), max.max(point))
+            });
+
+        let margin = PEN_WIDTH_MM;
+
+        (
+            Point {
+                x: min.x - margin,
+                y: min.y - margin,
+            },
+            Point {
+                x: max.x + margin,
+                y: max.y + margin,
+            },
+        )
+    }
+
+    /// Renders the drawing in `format`, returning the image and its MIME type.
+    pub fn render(&self, format: ImageFormat) -> (Vec<u8>, &'static str) {
+        match format {
+            ImageFormat::Svg => (self.to_svg().into_bytes(), "image/svg+xml"),
+            ImageFormat::Png => (self.to_png(), "image/png"),
+        }
+    }
+
+    /// Renders the drawing at its real size, in millimetres.
+    pub fn to_svg(&self) -> String {
+        let (min, max) = self.bounds();
+        let (width, height) = (max.x - min.x, max.y - min.y);
+
+        let mut path = String::new();
+        let mut pen_at = None;
+        for segment in &self.segments {
+            // Runs of joined segments become a single polyline.
+            if pen_at != Some(segment.from) {
+                write!(path, "M{} {}", segment.from.x, segment.from.y).unwrap();
+            }
+            write!(path, "L{} {}", segment.to.x, segment.to.y).unwrap();
+            pen_at = Some(segment.to);
+        }
+
+        format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}mm" height="{}mm" "#,
+                r#"viewBox="{} {} {} {}">"#,
+                r#"<path d="{}" fill="none" stroke="black" stroke-width="{}" "#,
+                r#"stroke-linecap="round" stroke-linejoin="round"/></svg>"#,
+            ),
+            width, height, min.x, min.y, width, height, path, PEN_WIDTH_MM,
+        )
+    }
+
+    /// Renders the drawing as a black and white PNG scaled to fit `PNG_SIZE`.
+    pub fn to_png(&self) -> Vec<u8> {
+        let (min, max) = self.bounds();
+        let scale = PNG_SIZE / (max.x - min.x).max(max.y - min.y);
+        let width = ((max.x - min.x) * scale).ceil() as usize;
+        let height = ((max.y - min.y) * scale).ceil() as usize;
+        let brush = (PEN_WIDTH_MM * scale).round().max(1.0) as i64;
+
+        let mut ink = vec![false; width * height];
+        let mut dab = |x: i64, y: i64| {
+            for y in y - brush / 2..y - brush / 2 + brush {
+                for x in x - brush / 2..x - brush / 2 + brush {
+                    if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                        ink[y as usize * width + x as usize] = true;
+                    }
+                }
+            }
+        };
+
+        let pixel = |point: Point| {
+            (
+                ((point.x - min.x) * scale) as i64,
+                ((point.y - min.y) * scale) as i64,
+            )
+        };
+        for segment in &self.segments {
+            let (mut x, mut y) = pixel(segment.from);
+            let (to_x, to_y) = pixel(segment.to);
+
+            // Bresenham's line algorithm.
+            let (dx, dy) = ((to_x - x).abs(), -(to_y - y).abs());
+            let (step_x, step_y) = ((to_x - x).signum(), (to_y - y).signum());
+            let mut error = dx + dy;
+            loop {
+                dab(x, y);
+                if (x, y) == (to_x, to_y) {
+                    break;
+                }
+                let doubled = 2 * error;
+                if doubled >= dy {
+                    error += dy;
+                    x += step_x;
+                }
+                if doubled <= dx {
+                    error += dx;
+                    y += step_y;
+                }
+            }
+        }
+
+        // Packed eight pixels to a byte, set where there is no ink.
+        let rows = ink
+            .chunks(width)
+            .flat_map(|row| row.chunks(8))
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, inked)| !**inked)
+                    .fold(0u8, |byte, (i, _)| byte | (0x80 >> i))
+            })
+            .collect::<Vec<_>>();
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        // Writing to memory cannot fail, and the rows match the header.
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&rows).unwrap();
+        writer.finish().unwrap();
+
+        png
+    }
+}
@@ -0,0 +1,321 @@
+use crate::ebb::{self, EbbCommand, EbbError, EbbResponse, MINIMUM_FIRMWARE_VERSION};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use std::{
+    collections::VecDeque,
+    io::{self, prelude::*, BufRead, BufReader, ErrorKind, Read},
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::Duration,
+};
+
+/// Somewhere to find an EBB: a real serial port, or a simulated board.
+pub trait Transport: Send {
+    /// Waits for the EBB to become available and returns separate halves for reading its replies
+    /// and writing commands. Returns `None` if `give_up` returns true first.
+    fn open(
+        &mut self,
+        give_up: &mut dyn FnMut() -> bool,
+    ) -> Option<(Box<dyn Read + Send>, Box<dyn Write + Send>)>;
+}
+
+/// An EBB plugged in over USB.
+pub struct SerialPortTransport {
+    /// Serial device to use. If none, the first EiBotBoard found is used.
+    pub device: Option<String>,
+}
+
+impl Transport for SerialPortTransport {
+    fn open(
+        &mut self,
+        give_up: &mut dyn FnMut() -> bool,
+    ) -> Option<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        println!("Waiting for serial connection...");
+
+        loop {
+            let port = get_serial_port(&self.device, give_up)?;
+            let name = port.name().unwrap_or("unknown".to_string());
+
+            match port.try_clone() {
+                Ok(writer) => {
+                    println!("Serial connection {} opened", name);
+                    return Some((Box::new(port), Box::new(writer)));
+                }
+                Err(e) => {
+                    println!("Could not share serial port {}: {}", name, e);
+                    sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+}
+
+/// The connection to the EBB. Commands are sent one at a time and each waits for its reply, but
+/// an `EmergencyStopper` can write `ES` at any moment, even while a reply is awaited.
+pub struct Serial {
+    transport: Box<dyn Transport>,
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Arc<Mutex<Writer>>,
+    /// Replies to emergency stops, read while waiting for something else.
+    emergency_stop_replies: VecDeque<Result<EbbResponse, EbbError>>,
+    /// Cleared when the port fails, after which nothing is sent until `reconnect`.
+    connected: bool,
+}
+
+struct Writer {
+    port: Box<dyn Write + Send>,
+    /// Emergency stops written since the last command, whose replies arrive ahead of its reply.
+    unread_emergency_stops: usize,
+    /// Set by an emergency stop, after which nothing else is sent until the stop's reply is taken.
+    stopped: bool,
+}
+
+/// Sends `ES` straight to the EBB, bypassing anything waiting to be sent.
+#[derive(Clone)]
+pub struct EmergencyStopper {
+    writer: Arc<Mutex<Writer>>,
+}
+
+impl Serial {
+    /// Waits until an EBB with supported firmware is connected.
+    pub fn connect(mut transport: Box<dyn Transport>) -> (Serial, EmergencyStopper) {
+        let (reader, writer) = transport.open(&mut || false).unwrap();
+        let writer = Arc::new(Mutex::new(Writer {
+            port: writer,
+            unread_emergency_stops: 0,
+            stopped: false,
+        }));
+
+        let mut serial = Serial {
+            transport,
+            reader: BufReader::new(reader),
+            writer: writer.clone(),
+            emergency_stop_replies: VecDeque::new(),
+            connected: true,
+        };
+
+        if let Err(error) = serial.check_firmware() {
+            println!("{}", error);
+            serial.reconnect(|| false);
+        }
+
+        (serial, EmergencyStopper { writer })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Reruns discovery until an EBB with supported firmware is back, checking `give_up` while it
+    /// waits. Returns false if it gave up.
+    pub fn reconnect(&mut self, mut give_up: impl FnMut() -> bool) -> bool {
+        loop {
+            let Some((reader, writer)) = self.transport.open(&mut give_up) else {
+                return false;
+            };
+            self.reader = BufReader::new(reader);
+
+            let mut old_writer = self.writer.lock().unwrap();
+            old_writer.port = writer;
+            // Emergency stops sent to the old port will never be answered; their callers were
+            // told so while waiting.
+            old_writer.unread_emergency_stops = 0;
+            old_writer.stopped = false;
+            self.emergency_stop_replies.clear();
+            drop(old_writer);
+            self.connected = true;
+
+            match self.check_firmware() {
+                Ok(()) => return true,
+                Err(error) => {
+                    println!("{}", error);
+                    sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    fn check_firmware(&mut self) -> Result<(), EbbError> {
+        let version = match self.send(&EbbCommand::Version)? {
+            EbbResponse::Version(version) => version,
+            response => {
+                return Err(EbbError::UnexpectedResponse {
+                    command: "V",
+                    response: format!("{:?}", response),
+                })
+            }
+        };
+
+        match ebb::firmware_version(&version) {
+            Some(number) if number >= MINIMUM_FIRMWARE_VERSION => {
+                println!("Connected to {}", version);
+                Ok(())
+            }
+            _ => {
+                self.connected = false;
+                Err(EbbError::UnsupportedFirmware(version))
+            }
+        }
+    }
+
+    pub fn send(&mut self, command: &EbbCommand) -> Result<EbbResponse, EbbError> {
+        if !self.connected {
+            return Err(EbbError::Disconnected("waiting to reconnect".to_string()));
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        // Checked under the same lock as the stop, so nothing can slip out after it.
+        if writer.stopped {
+            return Err(EbbError::EmergencyStopped);
+        }
+
+        println!("Writing to serial port: {}", command);
+
+        let written = writer
+            .port
+            .write_all(format!("{}\r", command).as_bytes())
+            .and_then(|()| writer.port.flush());
+        let emergency_stops = std::mem::take(&mut writer.unread_emergency_stops);
+        drop(writer);
+
+        if let Err(e) = written {
+            return Err(self.disconnected(e));
+        }
+
+        self.read_emergency_stop_replies(emergency_stops);
+
+        let response =
+            ebb::read_response(command, || self.read_line()).map_err(|error| match error {
+                EbbError::Disconnected(error) => EbbError::Unanswered(error),
+                error => error,
+            });
+
+        println!("Response from serial port: {:?}", &response);
+
+        response
+    }
+
+    /// Takes the reply to the oldest emergency stop not yet answered, after which commands can be
+    /// sent again.
+    pub fn emergency_stop_reply(&mut self) -> Result<EbbResponse, EbbError> {
+        let mut writer = self.writer.lock().unwrap();
+        let emergency_stops = std::mem::take(&mut writer.unread_emergency_stops);
+        writer.stopped = false;
+        drop(writer);
+        self.read_emergency_stop_replies(emergency_stops);
+
+        self.emergency_stop_replies
+            .pop_front()
+            .unwrap_or_else(|| Err(EbbError::Firmware("no emergency stop was sent".to_string())))
+    }
+
+    fn read_emergency_stop_replies(&mut self, count: usize) {
+        for _ in 0..count {
+            let response = ebb::read_response(&EMERGENCY_STOP, || self.read_line());
+
+            println!("Response to emergency stop: {:?}", &response);
+
+            self.emergency_stop_replies.push_back(response);
+        }
+    }
+
+    /// Blocks until a non-empty line arrives. The EBB terminates some replies with `\n\r` rather
+    /// than `\r\n`, so stray carriage returns are stripped from both ends.
+    fn read_line(&mut self) -> Result<String, EbbError> {
+        if !self.connected {
+            return Err(EbbError::Disconnected("waiting to reconnect".to_string()));
+        }
+
+        let mut line = String::new();
+
+        loop {
+            match self.reader.read_line(&mut line) {
+                // A closed port reads as end of file.
+                Ok(0) => {
+                    let error = io::Error::from(ErrorKind::UnexpectedEof);
+                    return Err(self.disconnected(error));
+                }
+                Ok(_) if line.ends_with('\n') => {
+                    let trimmed = line.trim_matches(|c| c == '\r' || c == '\n');
+                    if !trimmed.is_empty() {
+                        return Ok(trimmed.to_string());
+                    }
+                    line.clear();
+                }
+                Ok(_) => {}
+                // Long moves keep the EBB quiet for longer than the port's timeout.
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(self.disconnected(e)),
+            }
+        }
+    }
+
+    fn disconnected(&mut self, error: io::Error) -> EbbError {
+        self.connected = false;
+
+        EbbError::Disconnected(error.to_string())
+    }
+}
+
+const EMERGENCY_STOP: EbbCommand = EbbCommand::EmergencyStop {
+    disable_motors: None,
+};
+
+impl EmergencyStopper {
+    pub fn stop(&self) -> io::Result<()> {
+        println!("Writing to serial port: {}", EMERGENCY_STOP);
+
+        let mut writer = self.writer.lock().unwrap();
+        writer
+            .port
+            .write_all(format!("{}\r", EMERGENCY_STOP).as_bytes())?;
+        writer.port.flush()?;
+        writer.unread_emergency_stops += 1;
+        writer.stopped = true;
+
+        Ok(())
+    }
+}
+
+fn get_serial_port(
+    device: &Option<String>,
+    give_up: &mut dyn FnMut() -> bool,
+) -> Option<Box<dyn SerialPort>> {
+    let port_filter = |port_info: &&SerialPortInfo| {
+        if let Some(device) = device {
+            port_info.port_name == *device
+        } else if let SerialPortType::UsbPort(usb_port_info) = &port_info.port_type {
+            usb_port_info
+                .product
+                .as_ref()
+                .unwrap_or(&"".to_string())
+                .contains("EiBotBoard")
+        } else {
+            false
+        }
+    };
+
+    loop {
+        if give_up() {
+            return None;
+        }
+
+        let port_info = serialport::available_ports()
+            .unwrap_or_default()
+            .iter()
+            .find(port_filter)
+            .cloned();
+
+        if let Some(port_info) = port_info {
+            // The device can show up before it is ready to be opened.
+            match serialport::new(&port_info.port_name, 9600)
+                .timeout(Duration::from_secs(1))
+                .open()
+            {
+                Ok(port) => return Some(port),
+                Err(e) => println!("Could not open port {}: {}", &port_info.port_name, e),
+            }
+        }
+
+        sleep(Duration::from_secs(1));
+    }
+}
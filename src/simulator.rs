@@ -0,0 +1,485 @@
+use crate::{
+    ebb::{self, EbbCommand, PenState},
+    kinematics,
+    position::Position,
+    serial::Transport,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+/// How long a read waits before timing out, like the real serial port.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An EiBotBoard emulated in-process, for running the server without an AxiDraw. Each connection
+/// starts a fresh board, as if it had just been plugged in.
+pub struct Simulator;
+
+impl Transport for Simulator {
+    fn open(
+        &mut self,
+        _give_up: &mut dyn FnMut() -> bool,
+    ) -> Option<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let (input_sender, input_receiver) = channel();
+        let (output_sender, output_receiver) = channel();
+
+        spawn(move || Board::default().run(input_receiver, output_sender));
+
+        println!("Simulated EBB connected");
+
+        Some((
+            Box::new(SimulatorReader {
+                receiver: output_receiver,
+                pending: VecDeque::new(),
+            }),
+            Box::new(SimulatorWriter {
+                sender: input_sender,
+            }),
+        ))
+    }
+}
+
+struct SimulatorReader {
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+impl Read for SimulatorReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.receiver.recv_timeout(READ_TIMEOUT) {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.pending.len());
+        for (byte, pending) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *byte = pending;
+        }
+
+        Ok(count)
+    }
+}
+
+struct SimulatorWriter {
+    sender: Sender<Vec<u8>>,
+}
+
+impl Write for SimulatorWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A move accepted by the board, taking the motors from one position to the next.
+struct Move {
+    start: Instant,
+    end: Instant,
+    steps: (i32, i32),
+}
+
+impl Move {
+    /// Steps not yet taken at `now`.
+    fn remaining(&self, now: Instant) -> (i32, i32) {
+        let total = self.end.saturating_duration_since(self.start).as_secs_f64();
+        let left = self.end.saturating_duration_since(now).as_secs_f64();
+        let fraction = if total > 0.0 {
+            (left / total).min(1.0)
+        } else {
+            0.0
+        };
+
+        (
+            (self.steps.0 as f64 * fraction) as i32,
+            (self.steps.1 as f64 * fraction) as i32,
+        )
+    }
+}
+
+struct Board {
+    /// The executing move, then at most one waiting in the single-entry FIFO.
+    moves: VecDeque<Move>,
+    /// Where the motors will be once every accepted move has finished.
+    position: (i32, i32),
+    pen: PenState,
+    /// Microstep resolution of each motor, 0 when disabled.
+    enables: (u8, u8),
+    servo_powered: bool,
+    layer: u8,
+    node_count: u32,
+    nickname: String,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board {
+            moves: VecDeque::new(),
+            position: (0, 0),
+            pen: PenState::Up,
+            enables: (0, 0),
+            servo_powered: false,
+            layer: 0,
+            node_count: 0,
+            nickname: String::new(),
+        }
+    }
+}
+
+/// Commands received but not yet run, split into lines.
+#[derive(Default)]
+struct Input {
+    lines: VecDeque<String>,
+    partial: Vec<u8>,
+}
+
+impl Input {
+    fn extend(&mut self, bytes: Vec<u8>) {
+        for byte in bytes {
+            match byte {
+                b'\r' => {
+                    let line = String::from_utf8_lossy(&self.partial).into_owned();
+                    self.lines.push_back(line);
+                    self.partial.clear();
+                }
+                b'\n' => {}
+                _ => self.partial.push(byte),
+            }
+        }
+    }
+
+    /// Moves the first emergency stop waiting behind other commands to the front. Returns false if
+    /// there is none.
+    fn expedite_emergency_stop(&mut self) -> bool {
+        let index = self
+            .lines
+            .iter()
+            .position(|line| matches!(line.parse(), Ok(EbbCommand::EmergencyStop { .. })));
+
+        match index.and_then(|index| self.lines.remove(index)) {
+            Some(line) => {
+                self.lines.push_front(line);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Board {
+    fn run(mut self, input: Receiver<Vec<u8>>, output: Sender<Vec<u8>>) {
+        let mut received = Input::default();
+
+        loop {
+            let Some(line) = received.lines.pop_front() else {
+                match input.recv() {
+                    Ok(bytes) => received.extend(bytes),
+                    Err(_) => return,
+                }
+                continue;
+            };
+
+            // Commands that wait for the motors keep reading, so an emergency stop can cut in.
+            let mut stopped = false;
+            if let Some(ready_at) = line
+                .parse()
+                .ok()
+                .and_then(|command| self.ready_at(&command))
+            {
+                while let Some(wait) = ready_at.checked_duration_since(Instant::now()) {
+                    if received.expedite_emergency_stop() {
+                        stopped = true;
+                        break;
+                    }
+
+                    match input.recv_timeout(wait) {
+                        Ok(bytes) => received.extend(bytes),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+
+            // A move held back by an emergency stop is dropped, but acknowledged before the stop
+            // runs.
+            let reply = match line.parse() {
+                Ok(command) if stopped && is_queued(&command) => "OK\r\n".to_string(),
+                _ => self.execute(&line),
+            };
+            if !reply.is_empty() && output.send(reply.into_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// When `command` can run, if it has to wait: moves wait for room in the FIFO, and commands
+    /// that clear the step counters wait for the motors to stop.
+    fn ready_at(&mut self, command: &EbbCommand) -> Option<Instant> {
+        let now = Instant::now();
+        self.moves.retain(|queued| queued.end > now);
+
+        match command {
+            EbbCommand::ClearStepPosition | EbbCommand::EnableMotors { .. } => {
+                self.moves.back().map(|queued| queued.end)
+            }
+            command if is_queued(command) && self.moves.len() > 1 => {
+                self.moves.front().map(|queued| queued.end)
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs one command and returns everything the EBB would send back for it.
+    fn execute(&mut self, command: &str) -> String {
+        if command.trim().is_empty() {
+            return String::new();
+        }
+
+        let command = match command.parse::<EbbCommand>() {
+            Ok(command) => command,
+            Err(e) => return format!("!8 Err: {}\r\n", e),
+        };
+
+        let data = match &command {
+            EbbCommand::Bootloader | EbbCommand::Reboot => return String::new(),
+            EbbCommand::Check => {
+                return "Param1=0\r\nParam2=0\r\nParam3=0\r\nOK\r\n".to_string();
+            }
+            // Like the EBB, these clear the step counters once the motors have stopped.
+            EbbCommand::ClearStepPosition => {
+                self.moves.clear();
+                self.position = (0, 0);
+                None
+            }
+            EbbCommand::EnableMotors { enable1, enable2 } => {
+                self.moves.clear();
+                self.position = (0, 0);
+                let resolution = ebb::microstep_resolution(*enable1).unwrap_or(0);
+                let motor2 = match enable2 {
+                    Some(0) => 0,
+                    _ => resolution,
+                };
+                self.enables = (resolution, motor2);
+                None
+            }
+            EbbCommand::EmergencyStop { disable_motors } => {
+                let now = Instant::now();
+                let mut interrupted = false;
+                let mut fifo = (0, 0);
+                let mut remaining = (0, 0);
+
+                for queued in self.moves.drain(..) {
+                    let (left1, left2) = if queued.start > now {
+                        fifo = queued.steps;
+                        queued.steps
+                    } else if now < queued.end {
+                        interrupted = true;
+                        remaining = queued.remaining(now);
+                        remaining
+                    } else {
+                        (0, 0)
+                    };
+                    self.position.0 -= left1;
+                    self.position.1 -= left2;
+                }
+
+                if *disable_motors == Some(true) {
+                    self.enables = (0, 0);
+                }
+
+                Some(format!(
+                    "{},{},{},{},{}",
+                    u8::from(interrupted),
+                    fifo.0.abs(),
+                    fifo.1.abs(),
+                    remaining.0.abs(),
+                    remaining.1.abs()
+                ))
+            }
+            EbbCommand::HomeMove { .. }
+            | EbbCommand::LowLevelMove { .. }
+            | EbbCommand::LowLevelMoveTimed { .. }
+            | EbbCommand::MixedAxisMove { .. }
+            | EbbCommand::StepperMove { .. } => {
+                let from = Position {
+                    motor1: self.position.0,
+                    motor2: self.position.1,
+                    pen: self.pen,
+                    ..Position::default()
+                };
+                let to = from.after(&command);
+                self.queue_move(
+                    kinematics::duration(from, &command),
+                    (to.motor1 - from.motor1, to.motor2 - from.motor2),
+                );
+                None
+            }
+            EbbCommand::SetPen {
+                state, duration, ..
+            } => {
+                self.pen = *state;
+                self.move_pen(*duration);
+                None
+            }
+            EbbCommand::TogglePen { duration } => {
+                self.pen = match self.pen {
+                    PenState::Up => PenState::Down,
+                    PenState::Down => PenState::Up,
+                };
+                self.move_pen(*duration);
+                None
+            }
+            EbbCommand::SetServoPower { state, .. } => {
+                if let Some(state) = state {
+                    self.servo_powered = *state;
+                }
+                None
+            }
+            EbbCommand::SetLayer { layer } => {
+                self.layer = *layer;
+                None
+            }
+            EbbCommand::SetNodeCount { count } => {
+                self.node_count = *count;
+                None
+            }
+            EbbCommand::NodeCountIncrement => {
+                self.node_count = self.node_count.wrapping_add(1);
+                None
+            }
+            EbbCommand::NodeCountDecrement => {
+                self.node_count = self.node_count.saturating_sub(1);
+                None
+            }
+            EbbCommand::SetNickname { nickname } => {
+                self.nickname = nickname.clone();
+                None
+            }
+            EbbCommand::Reset => {
+                *self = Board::default();
+                None
+            }
+            EbbCommand::QueryButton => Some("0".to_string()),
+            EbbCommand::QueryCurrent => Some("0394,0300".to_string()),
+            EbbCommand::QueryEnables => Some(format!("{},{}", self.enables.0, self.enables.1)),
+            EbbCommand::QueryGeneral => {
+                // Only the pen bit (bit 4, set when up) is simulated.
+                return format!("{:02X}\r\n", u8::from(self.pen == PenState::Up) << 4);
+            }
+            EbbCommand::QueryLayer => Some(self.layer.to_string()),
+            EbbCommand::QueryMotors => {
+                let now = Instant::now();
+                self.moves.retain(|queued| queued.end > now);
+                let executing = self.moves.front();
+                let moving = executing.map_or((false, false), |queued| {
+                    (queued.steps.0 != 0, queued.steps.1 != 0)
+                });
+                return format!(
+                    "QM,{},{},{},{}\r\n",
+                    u8::from(executing.is_some()),
+                    u8::from(moving.0),
+                    u8::from(moving.1),
+                    u8::from(self.moves.len() > 1)
+                );
+            }
+            EbbCommand::QueryNodeCount => Some(self.node_count.to_string()),
+            EbbCommand::QueryPen => Some(u8::from(self.pen == PenState::Up).to_string()),
+            EbbCommand::QueryServoPower => Some(u8::from(self.servo_powered).to_string()),
+            EbbCommand::QueryStepPosition => {
+                let (motor1, motor2) = self.current_position();
+                Some(format!("{},{}", motor1, motor2))
+            }
+            EbbCommand::QueryNickname => Some(self.nickname.clone()),
+            EbbCommand::Version => {
+                return "EBBv13_and_above EB Firmware Version 3.0.2\r\n".to_string();
+            }
+            EbbCommand::AnalogConfigure { .. }
+            | EbbCommand::ConfigureUser { .. }
+            | EbbCommand::ServoOutput { .. }
+            | EbbCommand::StepperServoConfigure { .. }
+            | EbbCommand::SetEngraver { .. } => None,
+        };
+
+        match data {
+            Some(data) => format!("{}\r\nOK\r\n", data),
+            None => "OK\r\n".to_string(),
+        }
+    }
+
+    /// Accepts a move into the FIFO, which has room once `ready_at` has passed, just as the EBB
+    /// holds back its `OK` until then.
+    fn queue_move(&mut self, duration: Duration, steps: (i32, i32)) {
+        let now = Instant::now();
+        self.moves.retain(|queued| queued.end > now);
+
+        if self.enables == (0, 0) {
+            self.enables = (16, 16);
+        }
+
+        let start = self
+            .moves
+            .back()
+            .map_or(Instant::now(), |queued| queued.end)
+            .max(Instant::now());
+        self.moves.push_back(Move {
+            start,
+            end: start + duration,
+            steps,
+        });
+        self.position.0 += steps.0;
+        self.position.1 += steps.1;
+    }
+
+    fn move_pen(&mut self, duration: Option<u16>) {
+        self.servo_powered = true;
+        self.queue_move(
+            Duration::from_millis(u64::from(duration.unwrap_or(0))),
+            (0, 0),
+        );
+    }
+
+    /// Where the motors are right now, partway through any move.
+    fn current_position(&self) -> (i32, i32) {
+        let now = Instant::now();
+
+        self.moves
+            .iter()
+            .map(|queued| {
+                if queued.start > now {
+                    queued.steps
+                } else {
+                    queued.remaining(now)
+                }
+            })
+            .fold(self.position, |(motor1, motor2), (left1, left2)| {
+                (motor1 - left1, motor2 - left2)
+            })
+    }
+}
+
+/// Whether `command` goes through the motion FIFO.
+fn is_queued(command: &EbbCommand) -> bool {
+    matches!(
+        command,
+        EbbCommand::HomeMove { .. }
+            | EbbCommand::LowLevelMove { .. }
+            | EbbCommand::LowLevelMoveTimed { .. }
+            | EbbCommand::MixedAxisMove { .. }
+            | EbbCommand::StepperMove { .. }
+            | EbbCommand::SetPen { .. }
+            | EbbCommand::TogglePen { .. }
+    )
+}
@@ -0,0 +1,601 @@
+use crate::{
+    ebb::{EbbCommand, PenState, MAX_STEPS_PER_MS},
+    position::{Point, Position},
+};
+use roxmltree::{Document, Node, ParsingOptions};
+use std::{fmt, str::FromStr};
+use svgtypes::{
+    Length, PointsParser, SimplePathSegment, SimplifyingPathParser, Transform, ViewBox,
+};
+
+/// Furthest a curve may stray from the lines it is drawn with.
+const TOLERANCE_MM: f64 = 0.1;
+
+/// Most lines a single curve is drawn with, however large it is.
+const MAX_CURVE_LINES: usize = 1000;
+
+/// Drawing speed with the pen down.
+const PEN_DOWN_MM_PER_SECOND: f64 = 25.0;
+
+/// Travel speed with the pen up, between lines and back to the origin.
+const PEN_UP_MM_PER_SECOND: f64 = 75.0;
+
+/// Time for the pen to settle after being raised or lowered.
+const PEN_DELAY_MS: u16 = 150;
+
+/// `<use>` elements referring to `<use>` elements are followed this deep, in case they form a
+/// loop.
+const MAX_USE_DEPTH: usize = 8;
+
+/// Width and height of the paper, with its top left corner at the origin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaperSize {
+    pub width_mm: f64,
+    pub height_mm: f64,
+}
+
+impl Default for PaperSize {
+    /// A4 in landscape, which fits within every AxiDraw's travel.
+    fn default() -> Self {
+        PaperSize {
+            width_mm: 297.0,
+            height_mm: 210.0,
+        }
+    }
+}
+
+impl FromStr for PaperSize {
+    type Err = String;
+
+    /// Parses `WIDTHxHEIGHT` in millimetres, e.g. `297x210`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| format!("expected WIDTHxHEIGHT in mm, got '{}'", s))?;
+        let dimension = |value: &str| match value.trim().parse::<f64>() {
+            Ok(mm) if mm > 0.0 && mm.is_finite() => Ok(mm),
+            _ => Err(format!("'{}' is not a positive number of mm", value)),
+        };
+
+        Ok(PaperSize {
+            width_mm: dimension(width)?,
+            height_mm: dimension(height)?,
+        })
+    }
+}
+
+impl fmt::Display for PaperSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width_mm, self.height_mm)
+    }
+}
+
+/// The lines of an SVG drawing, in millimetres from the top left corner of the paper.
+#[derive(Debug, Default)]
+pub struct Drawing {
+    pub polylines: Vec<Vec<Point>>,
+}
+
+impl Drawing {
+    /// Flattens the visible paths and shapes of `svg` into lines, scaled so its `viewBox` fits
+    /// `paper` with their top left corners together. Anything outside the `viewBox` is clipped,
+    /// as it would be on screen. Text and images are not drawn. Points too far away to represent
+    /// are an error, and curves are drawn with at most `MAX_CURVE_LINES` lines each.
+    pub fn parse(svg: &str, paper: PaperSize) -> Result<Drawing, String> {
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+        let document = Document::parse_with_options(svg, options)
+            .map_err(|e| format!("Invalid SVG document: {}", e))?;
+        let root = document.root_element();
+        if !root.has_tag_name("svg") {
+            return Err(format!(
+                "Expected an <svg> document, got <{}>",
+                root.tag_name().name()
+            ));
+        }
+
+        let view_box = view_box(root)?;
+        let scale = (paper.width_mm / view_box.w).min(paper.height_mm / view_box.h);
+        let to_paper = Transform::new(
+            scale,
+            0.0,
+            0.0,
+            scale,
+            -view_box.x * scale,
+            -view_box.y * scale,
+        );
+        let visible = Point {
+            x: view_box.w * scale,
+            y: view_box.h * scale,
+        };
+
+        let mut drawing = Drawing::default();
+        for child in root.children() {
+            drawing.add(child, &to_paper, visible, 0)?;
+        }
+        drawing.polylines = drawing
+            .polylines
+            .iter()
+            .flat_map(|polyline| clip(polyline, visible))
+            .collect();
+
+        if drawing.polylines.is_empty() {
+            return Err("The SVG has no visible lines to draw".to_string());
+        }
+
+        Ok(drawing)
+    }
+
+    fn add(
+        &mut self,
+        node: Node,
+        parent: &Transform,
+        visible: Point,
+        depth: usize,
+    ) -> Result<(), String> {
+        if !node.is_element() || is_hidden(node) {
+            return Ok(());
+        }
+
+        let transform = match node.attribute("transform").map(Transform::from_str) {
+            Some(Ok(transform)) => then(parent, &transform),
+            // Browsers skip elements with a transform they cannot parse.
+            Some(Err(_)) => return Ok(()),
+            None => *parent,
+        };
+
+        let data = match node.tag_name().name() {
+            "svg" | "g" | "a" | "switch" => {
+                // A nested <svg> is positioned by its x and y.
+                let offset =
+                    Transform::new(1.0, 0.0, 0.0, 1.0, number(node, "x"), number(node, "y"));
+                let transform = then(&transform, &offset);
+                for child in node.children() {
+                    self.add(child, &transform, visible, depth)?;
+                }
+                return Ok(());
+            }
+            "use" => {
+                let referenced = node
+                    .attribute("href")
+                    .or_else(|| node.attribute(("http://www.w3.org/1999/xlink", "href")))
+                    .and_then(|href| href.strip_prefix('#'))
+                    .and_then(|id| {
+                        node.document()
+                            .descendants()
+                            .find(|node| node.attribute("id") == Some(id))
+                    });
+                let Some(referenced) = referenced.filter(|_| depth < MAX_USE_DEPTH) else {
+                    return Ok(());
+                };
+                let offset =
+                    Transform::new(1.0, 0.0, 0.0, 1.0, number(node, "x"), number(node, "y"));
+                let transform = then(&transform, &offset);
+                // A <symbol> is only drawn where it is used.
+                if referenced.has_tag_name("symbol") {
+                    for child in referenced.children() {
+                        self.add(child, &transform, visible, depth + 1)?;
+                    }
+                } else {
+                    self.add(referenced, &transform, visible, depth + 1)?;
+                }
+                return Ok(());
+            }
+            "path" => node.attribute("d").unwrap_or_default().to_string(),
+            "line" => format!(
+                "M {} {} L {} {}",
+                number(node, "x1"),
+                number(node, "y1"),
+                number(node, "x2"),
+                number(node, "y2")
+            ),
+            "polyline" | "polygon" => {
+                let points = PointsParser::from(node.attribute("points").unwrap_or_default())
+                    .map(|(x, y)| format!("{} {}", x, y))
+                    .collect::<Vec<_>>();
+                let close = if node.has_tag_name("polygon") {
+                    " Z"
+                } else {
+                    ""
+                };
+                format!("M {}{}", points.join(" L "), close)
+            }
+            "rect" => rect(node),
+            "circle" => {
+                let r = number(node, "r");
+                ellipse(number(node, "cx"), number(node, "cy"), r, r)
+            }
+            "ellipse" => ellipse(
+                number(node, "cx"),
+                number(node, "cy"),
+                number(node, "rx"),
+                number(node, "ry"),
+            ),
+            // Definitions, text, images and metadata draw nothing by themselves.
+            _ => return Ok(()),
+        };
+
+        self.add_path(&data, &transform, visible)
+    }
+
+    fn add_path(
+        &mut self,
+        data: &str,
+        transform: &Transform,
+        visible: Point,
+    ) -> Result<(), String> {
+        let mut polyline = Vec::new();
+        let mut start = Point { x: 0.0, y: 0.0 };
+
+        // Like a browser, draws the path up to the first error in it.
+        for segment in SimplifyingPathParser::from(data).map_while(Result::ok) {
+            let from = polyline.last().copied().unwrap_or(start);
+            match segment {
+                SimplePathSegment::MoveTo { x, y } => {
+                    self.finish(&mut polyline);
+                    start = apply(transform, x, y)?;
+                    polyline.push(start);
+                }
+                SimplePathSegment::LineTo { x, y } => polyline.push(apply(transform, x, y)?),
+                SimplePathSegment::CurveTo {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    x,
+                    y,
+                } => {
+                    let control1 = apply(transform, x1, y1)?;
+                    let control2 = apply(transform, x2, y2)?;
+                    let to = apply(transform, x, y)?;
+                    if off_paper(&[from, control1, control2, to], visible) {
+                        polyline.push(to);
+                        continue;
+                    }
+                    // The curve strays from its chords by at most 3/4 of its control polygon's
+                    // sharpest bend, divided by the square of the number of chords.
+                    let bend = bend(from, control1, control2).max(bend(control1, control2, to));
+                    for t in steps(0.75 * bend) {
+                        let u = 1.0 - t;
+                        polyline.push(Point {
+                            x: u * u * u * from.x
+                                + 3.0 * u * u * t * control1.x
+                                + 3.0 * u * t * t * control2.x
+                                + t * t * t * to.x,
+                            y: u * u * u * from.y
+                                + 3.0 * u * u * t * control1.y
+                                + 3.0 * u * t * t * control2.y
+                                + t * t * t * to.y,
+                        });
+                    }
+                }
+                SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                    let control = apply(transform, x1, y1)?;
+                    let to = apply(transform, x, y)?;
+                    if off_paper(&[from, control, to], visible) {
+                        polyline.push(to);
+                        continue;
+                    }
+                    for t in steps(0.25 * bend(from, control, to)) {
+                        let u = 1.0 - t;
+                        polyline.push(Point {
+                            x: u * u * from.x + 2.0 * u * t * control.x + t * t * to.x,
+                            y: u * u * from.y + 2.0 * u * t * control.y + t * t * to.y,
+                        });
+                    }
+                }
+                SimplePathSegment::ClosePath => {
+                    if !polyline.is_empty() {
+                        polyline.push(start);
+                    }
+                    self.finish(&mut polyline);
+                }
+            }
+        }
+
+        self.finish(&mut polyline);
+
+        Ok(())
+    }
+
+    fn finish(&mut self, polyline: &mut Vec<Point>) {
+        let polyline = std::mem::take(polyline);
+        if polyline.len() > 1 {
+            self.polylines.push(polyline);
+        }
+    }
+
+    /// Commands that draw the lines with the pen, starting and ending at `origin` with the pen up.
+    /// `HM` moves there first, so the drawing lands in the same place wherever the carriage was.
+    pub fn commands(&self, origin: Position, steps_per_mm: f64) -> Vec<EbbCommand> {
+        let home = EbbCommand::HomeMove {
+            step_frequency: (PEN_UP_MM_PER_SECOND * steps_per_mm).clamp(2.0, 25_000.0) as u16,
+            position: Some((origin.motor1, origin.motor2)).filter(|&position| position != (0, 0)),
+        };
+        let mut commands = vec![pen(PenState::Up), home.clone()];
+
+        let motors = |point: Point| {
+            let x = point.x * steps_per_mm;
+            let y = point.y * steps_per_mm;
+            (
+                origin.motor1 + (x + y).round() as i32,
+                origin.motor2 + (x - y).round() as i32,
+            )
+        };
+        let mut at = (origin.motor1, origin.motor2);
+        let mut pen_down = false;
+
+        for polyline in &self.polylines {
+            let first = motors(polyline[0]);
+            // Lines that carry on from where the last ended are drawn without lifting the pen.
+            if first != at {
+                if pen_down {
+                    commands.push(pen(PenState::Up));
+                    pen_down = false;
+                }
+                commands.extend(move_to(&mut at, first, PEN_UP_MM_PER_SECOND, steps_per_mm));
+            }
+            if !pen_down {
+                commands.push(pen(PenState::Down));
+                pen_down = true;
+            }
+            for &point in &polyline[1..] {
+                commands.extend(move_to(
+                    &mut at,
+                    motors(point),
+                    PEN_DOWN_MM_PER_SECOND,
+                    steps_per_mm,
+                ));
+            }
+        }
+
+        if pen_down {
+            commands.push(pen(PenState::Up));
+        }
+        commands.push(home);
+
+        commands
+    }
+}
+
+/// The area of user space drawn on the page: the `viewBox`, or the width and height in user
+/// units if there is none.
+fn view_box(root: Node) -> Result<ViewBox, String> {
+    let view_box = match root.attribute("viewBox") {
+        Some(view_box) => ViewBox::from_str(view_box)
+            .map_err(|e| format!("Invalid viewBox '{}': {}", view_box, e))?,
+        None => ViewBox::new(0.0, 0.0, number(root, "width"), number(root, "height")),
+    };
+
+    if view_box.w > 0.0 && view_box.h > 0.0 {
+        Ok(view_box)
+    } else {
+        Err("The SVG needs a viewBox, or a width and height, to be scaled to the paper".to_string())
+    }
+}
+
+fn is_hidden(node: Node) -> bool {
+    let hidden_by_style = node.attribute("style").is_some_and(|style| {
+        style.split(';').any(|declaration| {
+            declaration
+                .split_once(':')
+                .is_some_and(|(name, value)| name.trim() == "display" && value.trim() == "none")
+        })
+    });
+
+    hidden_by_style || node.attribute("display") == Some("none")
+}
+
+/// A length attribute in user units, or zero if missing or invalid.
+fn number(node: Node, name: &str) -> f64 {
+    node.attribute(name)
+        .and_then(|value| Length::from_str(value).ok())
+        .map_or(0.0, |length| length.number)
+}
+
+fn rect(node: Node) -> String {
+    let (x, y) = (number(node, "x"), number(node, "y"));
+    let (width, height) = (number(node, "width"), number(node, "height"));
+    if width <= 0.0 || height <= 0.0 {
+        return String::new();
+    }
+
+    // A missing corner radius takes the other's value.
+    let (rx, ry) = match (node.attribute("rx"), node.attribute("ry")) {
+        (None, None) => (0.0, 0.0),
+        (Some(_), None) => (number(node, "rx"), number(node, "rx")),
+        (None, Some(_)) => (number(node, "ry"), number(node, "ry")),
+        (Some(_), Some(_)) => (number(node, "rx"), number(node, "ry")),
+    };
+    let rx = rx.clamp(0.0, width / 2.0);
+    let ry = ry.clamp(0.0, height / 2.0);
+    if rx == 0.0 || ry == 0.0 {
+        return format!("M {} {} h {} v {} h {} Z", x, y, width, height, -width);
+    }
+
+    format!(
+        "M {} {} h {} a {rx} {ry} 0 0 1 {rx} {ry} v {} a {rx} {ry} 0 0 1 {} {ry} \
+         h {} a {rx} {ry} 0 0 1 {} {} v {} a {rx} {ry} 0 0 1 {rx} {} Z",
+        x + rx,
+        y,
+        width - 2.0 * rx,
+        height - 2.0 * ry,
+        -rx,
+        -(width - 2.0 * rx),
+        -rx,
+        -ry,
+        -(height - 2.0 * ry),
+        -ry,
+    )
+}
+
+fn ellipse(cx: f64, cy: f64, rx: f64, ry: f64) -> String {
+    if rx <= 0.0 || ry <= 0.0 {
+        return String::new();
+    }
+
+    format!(
+        "M {} {cy} A {rx} {ry} 0 1 0 {} {cy} A {rx} {ry} 0 1 0 {} {cy} Z",
+        cx - rx,
+        cx + rx,
+        cx - rx
+    )
+}
+
+/// `child` followed by `parent`, as for an element inside a transformed group.
+fn then(parent: &Transform, child: &Transform) -> Transform {
+    Transform::new(
+        parent.a * child.a + parent.c * child.b,
+        parent.b * child.a + parent.d * child.b,
+        parent.a * child.c + parent.c * child.d,
+        parent.b * child.c + parent.d * child.d,
+        parent.a * child.e + parent.c * child.f + parent.e,
+        parent.b * child.e + parent.d * child.f + parent.f,
+    )
+}
+
+/// Where `x`,`y` lands on the paper. An error if it is too far away to be represented.
+fn apply(transform: &Transform, x: f64, y: f64) -> Result<Point, String> {
+    let point = Point {
+        x: transform.a * x + transform.c * y + transform.e,
+        y: transform.b * x + transform.d * y + transform.f,
+    };
+
+    if point.x.is_finite() && point.y.is_finite() {
+        Ok(point)
+    } else {
+        Err(format!(
+            "The SVG has a point too far away to draw: {},{}",
+            x, y
+        ))
+    }
+}
+
+/// Whether a curve with these control points misses the paper, as it does if they all lie
+/// beyond the same edge, since the curve stays within their convex hull.
+fn off_paper(controls: &[Point], visible: Point) -> bool {
+    controls.iter().all(|point| point.x < 0.0)
+        || controls.iter().all(|point| point.y < 0.0)
+        || controls.iter().all(|point| point.x > visible.x)
+        || controls.iter().all(|point| point.y > visible.y)
+}
+
+/// How sharply the control polygon turns at `b`.
+fn bend(a: Point, b: Point, c: Point) -> f64 {
+    (a.x - 2.0 * b.x + c.x).hypot(a.y - 2.0 * b.y + c.y)
+}
+
+/// Evenly spaced points along a curve, ending at 1, enough that its chords stay within the
+/// tolerance given the curve strays `error` from a single chord, up to `MAX_CURVE_LINES`.
+fn steps(error: f64) -> impl Iterator<Item = f64> {
+    let count = (error / TOLERANCE_MM).sqrt().ceil();
+    // NaN compares false, leaving a single chord.
+    let count = if count > 1.0 {
+        count.min(MAX_CURVE_LINES as f64) as usize
+    } else {
+        1
+    };
+
+    (1..=count).map(move |step| step as f64 / count as f64)
+}
+
+/// The parts of `polyline` within the rectangle from the top left corner to `corner`.
+fn clip(polyline: &[Point], corner: Point) -> Vec<Vec<Point>> {
+    let mut parts = Vec::new();
+    let mut part: Vec<Point> = Vec::new();
+
+    for pair in polyline.windows(2) {
+        let Some((from, to)) = clip_line(pair[0], pair[1], corner) else {
+            continue;
+        };
+        if part.last() != Some(&from) {
+            if part.len() > 1 {
+                parts.push(std::mem::take(&mut part));
+            }
+            part = vec![from];
+        }
+        part.push(to);
+    }
+    if part.len() > 1 {
+        parts.push(part);
+    }
+
+    parts
+}
+
+/// Liang-Barsky clipping of the line from `from` to `to`.
+fn clip_line(from: Point, to: Point, corner: Point) -> Option<(Point, Point)> {
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+    let (mut enter, mut leave) = (0.0_f64, 1.0_f64);
+
+    for (p, q) in [
+        (-dx, from.x),
+        (dx, corner.x - from.x),
+        (-dy, from.y),
+        (dy, corner.y - from.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            enter = enter.max(q / p);
+        } else {
+            leave = leave.min(q / p);
+        }
+    }
+    if enter > leave {
+        return None;
+    }
+
+    let at = |t: f64| Point {
+        x: from.x + t * dx,
+        y: from.y + t * dy,
+    };
+    let from = if enter > 0.0 { at(enter) } else { from };
+    let to = if leave < 1.0 { at(leave) } else { to };
+
+    Some((from, to))
+}
+
+fn pen(state: PenState) -> EbbCommand {
+    EbbCommand::SetPen {
+        state,
+        duration: Some(PEN_DELAY_MS),
+        port_b_pin: None,
+    }
+}
+
+/// An `SM` from `at` to `to` at `mm_per_second`, no faster than the EBB can step. None if the
+/// motors are already there.
+fn move_to(
+    at: &mut (i32, i32),
+    to: (i32, i32),
+    mm_per_second: f64,
+    steps_per_mm: f64,
+) -> Option<EbbCommand> {
+    let steps1 = to.0 - at.0;
+    let steps2 = to.1 - at.1;
+    if (steps1, steps2) == (0, 0) {
+        return None;
+    }
+    *at = to;
+
+    let x = f64::from(steps1 + steps2) / 2.0;
+    let y = f64::from(steps1 - steps2) / 2.0;
+    let distance_mm = x.hypot(y) / steps_per_mm;
+    let fastest =
+        f64::from(steps1.unsigned_abs().max(steps2.unsigned_abs())) / MAX_STEPS_PER_MS as f64;
+    let duration = (distance_mm / mm_per_second * 1000.0)
+        .max(fastest)
+        .ceil()
+        .max(1.0);
+
+    Some(EbbCommand::StepperMove {
+        duration: duration as u32,
+        steps1,
+        steps2: Some(steps2),
+    })
+}
@@ -0,0 +1,122 @@
+use crate::{ebb::EbbCommand, job::PushError, parse_command, AxidrawService, SHUTTING_DOWN};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// Accepts connections speaking a plain line protocol: each line is an EBB command that gets
+/// buffered exactly as if it had been streamed over gRPC, and is answered with `OK` or an
+/// EBB-style `!` error. Each connection is one job, opened by its first command and kept open
+/// until the client hangs up, so a connection that sends nothing does not hold up the queue.
+pub async fn serve(service: Arc<AxidrawService>, port: u16) {
+    let listener = TcpListener::bind((IpAddr::from_str("::").unwrap(), port))
+        .await
+        .unwrap_or_else(|e| panic!("Could not listen on TCP port {}: {}", port, e));
+
+    loop {
+        let connection = tokio::select! {
+            connection = listener.accept() => connection,
+            _ = service.shutdown_started() => return,
+        };
+        let (stream, address) = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("Failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        println!("TCP connection from {} opened", address);
+
+        let service = service.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = handle_connection(&service, stream, address).await {
+                println!("TCP connection from {} failed: {}", address, e);
+            }
+
+            println!("TCP connection from {} closed", address);
+        });
+    }
+}
+
+async fn handle_connection(
+    service: &AxidrawService,
+    stream: TcpStream,
+    address: SocketAddr,
+) -> std::io::Result<()> {
+    let mut job_id = None;
+    let result = handle_lines(service, stream, address, &mut job_id).await;
+    if let Some(job_id) = job_id {
+        service.close_job(job_id).await;
+    }
+
+    result
+}
+
+async fn handle_lines(
+    service: &AxidrawService,
+    stream: TcpStream,
+    address: SocketAddr,
+    job_id: &mut Option<u64>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match parse_command(line) {
+            Ok(_) if service.is_shutting_down() => format!("!{}", SHUTTING_DOWN),
+            Ok(command) => match enqueue(service, address, job_id, command).await {
+                Ok(()) => "OK".to_string(),
+                Err(PushError::Closed) => format!("!{}", SHUTTING_DOWN),
+                Err(error) => format!("!{}", error),
+            },
+            Err(error) => format!("!{}", error),
+        };
+
+        writer
+            .write_all(format!("{}\r\n", reply).as_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Adds a command to the connection's job, opening it with the first command. The job is gone if
+/// the queue was cleared, so the client carries on in a fresh one; once cancelled, the rest of
+/// the client's commands are refused.
+async fn enqueue(
+    service: &AxidrawService,
+    address: SocketAddr,
+    job_id: &mut Option<u64>,
+    command: EbbCommand,
+) -> Result<(), PushError> {
+    if let Some(id) = *job_id {
+        match service.enqueue(id, command.clone()).await {
+            Err(PushError::Removed) => {}
+            result => return result,
+        }
+    }
+
+    let id = service
+        .open_job(Some(format!("tcp-{}", address)), address.to_string())
+        .await;
+    let result = service.enqueue(id, command).await;
+    if result.is_ok() {
+        *job_id = Some(id);
+    } else {
+        // Nothing is left to hold the queue for.
+        service.close_job(id).await;
+    }
+
+    result
+}
@@ -0,0 +1,1543 @@
+//! End-to-end tests running the gRPC service against the simulated EBB, checking exactly what is
+//! written to the serial side.
+
+use crate::{
+    axidraw_over_http::{
+        axidraw_over_http_client::AxidrawOverHttpClient,
+        axidraw_over_http_server::AxidrawOverHttpServer, BufferState, Command, Empty,
+        RunningStatus,
+    },
+    axidraw_over_tcp::{
+        axidraw_over_tcp_client::AxidrawOverTcpClient,
+        axidraw_over_tcp_server::AxidrawOverTcpServer, query_reply::Reply, Bounds, DryRunReport,
+        EmergencyStopRequest, ImageFormat, InvalidCommand, JobInfo, JobRequest, MoveJobRequest,
+        PlotterState, PreviewReply, PreviewRequest, ReturnHomeRequest, SetOriginRequest,
+        StepPosition, SvgRequest,
+    },
+    begin_shutdown, http, initial_status,
+    job::JobQueue,
+    journal::{Journal, JournalEntry},
+    limits::{Model, TravelLimits},
+    position::{Position, Scale},
+    serial::Transport,
+    simulator::Simulator,
+    start,
+    svg::PaperSize,
+    tcp, AxidrawService, ControlMessage,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::{sleep, Instant},
+};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
+use tonic::{
+    transport::{Channel, Server},
+    Code,
+};
+
+/// How long to wait for the consumer thread before giving up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every connection starts by checking the firmware version.
+const HANDSHAKE: &str = "V\r";
+
+/// A simulated EBB that records every byte written to it.
+struct RecordingTransport {
+    written: Arc<Mutex<Vec<u8>>>,
+    interference: Arc<Mutex<Option<String>>>,
+    rejection: Arc<Mutex<Option<String>>>,
+    unplug: Arc<Mutex<Option<Unplug>>>,
+}
+
+/// When the simulated EBB is unplugged, after which a fresh one is plugged back in.
+#[derive(Clone, Copy)]
+enum Unplug {
+    /// Writing the next command fails, so it never reaches the EBB.
+    BeforeWrite,
+    /// The next command is written, but the port closes before its reply.
+    BeforeReply,
+}
+
+impl Transport for RecordingTransport {
+    fn open(
+        &mut self,
+        give_up: &mut dyn FnMut() -> bool,
+    ) -> Option<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let (reader, writer) = Simulator.open(give_up)?;
+        let unanswered = Arc::new(AtomicUsize::new(0));
+        let rejected = Arc::new(Mutex::new(None));
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = TamperingReader {
+            reader: BufReader::new(reader),
+            unanswered: unanswered.clone(),
+            rejected: rejected.clone(),
+            closed: closed.clone(),
+            pending: Vec::new(),
+        };
+        let writer = RecordingWriter {
+            writer,
+            written: self.written.clone(),
+            interference: self.interference.clone(),
+            unanswered,
+            rejection: self.rejection.clone(),
+            rejected,
+            unplug: self.unplug.clone(),
+            closed,
+        };
+
+        Some((Box::new(reader), Box::new(writer)))
+    }
+}
+
+struct RecordingWriter {
+    writer: Box<dyn Write + Send>,
+    written: Arc<Mutex<Vec<u8>>>,
+    /// A command slipped to the board ahead of the next one, unrecorded, as if another program
+    /// had talked to it.
+    interference: Arc<Mutex<Option<String>>>,
+    unanswered: Arc<AtomicUsize>,
+    /// A firmware error to answer the next command with, in place of its real reply.
+    rejection: Arc<Mutex<Option<String>>>,
+    rejected: Arc<Mutex<Option<String>>>,
+    unplug: Arc<Mutex<Option<Unplug>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.unplug.lock().unwrap().take() {
+            Some(Unplug::BeforeWrite) => {
+                self.closed.store(true, Ordering::SeqCst);
+                return Err(io::Error::from(ErrorKind::BrokenPipe));
+            }
+            Some(Unplug::BeforeReply) => self.closed.store(true, Ordering::SeqCst),
+            None => {}
+        }
+
+        if let Some(command) = self.interference.lock().unwrap().take() {
+            self.writer.write_all(format!("{}\r", command).as_bytes())?;
+            self.unanswered.fetch_add(1, Ordering::SeqCst);
+        }
+
+        if let Some(error) = self.rejection.lock().unwrap().take() {
+            *self.rejected.lock().unwrap() = Some(error);
+        }
+
+        let count = self.writer.write(buf)?;
+        self.written
+            .lock()
+            .unwrap()
+            .extend_from_slice(&buf[..count]);
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Drops the replies to interfering commands, which the service never asked for, swaps in any
+/// rejection for the real reply, and reads as closed once unplugged.
+struct TamperingReader {
+    reader: BufReader<Box<dyn Read + Send>>,
+    unanswered: Arc<AtomicUsize>,
+    rejected: Arc<Mutex<Option<String>>>,
+    closed: Arc<AtomicBool>,
+    /// What is left of a rejection to hand out.
+    pending: Vec<u8>,
+}
+
+impl TamperingReader {
+    fn skip_reply(&mut self) -> io::Result<()> {
+        loop {
+            let mut reply = String::new();
+            match self.reader.read_line(&mut reply) {
+                Ok(_) => return Ok(()),
+                // The reply may still be on its way.
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Read for TamperingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+
+        while self.unanswered.load(Ordering::SeqCst) > 0 {
+            self.skip_reply()?;
+            self.unanswered.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        let rejected = self.rejected.lock().unwrap().take();
+        if let Some(error) = rejected {
+            self.skip_reply()?;
+            self.pending = format!("{}\r\n", error).into_bytes();
+        }
+
+        if !self.pending.is_empty() {
+            let count = buf.len().min(self.pending.len());
+            buf[..count].copy_from_slice(&self.pending[..count]);
+            self.pending.drain(..count);
+            return Ok(count);
+        }
+
+        self.reader.read(buf)
+    }
+}
+
+struct Harness {
+    service: Arc<AxidrawService>,
+    client: AxidrawOverHttpClient<Channel>,
+    extensions: AxidrawOverTcpClient<Channel>,
+    written: Arc<Mutex<Vec<u8>>>,
+    interference: Arc<Mutex<Option<String>>>,
+    rejection: Arc<Mutex<Option<String>>>,
+    unplug: Arc<Mutex<Option<Unplug>>>,
+    // Held so the service does not see a shutdown until `shut_down`.
+    shutdown_sender: watch::Sender<bool>,
+    consumer_thread: Option<JoinHandle<()>>,
+}
+
+impl Harness {
+    /// Serves the gRPC service on an ephemeral port, in front of a fresh simulated EBB.
+    async fn start() -> Harness {
+        Harness::with_limits(None).await
+    }
+
+    async fn with_limits(limits: Option<TravelLimits>) -> Harness {
+        Harness::with(JobQueue::default(), limits).await
+    }
+
+    async fn with(job_queue: JobQueue, limits: Option<TravelLimits>) -> Harness {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let interference = Arc::new(Mutex::new(None));
+        let rejection = Arc::new(Mutex::new(None));
+        let unplug = Arc::new(Mutex::new(None));
+        let transport = RecordingTransport {
+            written: written.clone(),
+            interference: interference.clone(),
+            rejection: rejection.clone(),
+            unplug: unplug.clone(),
+        };
+        let running_status = initial_status(&job_queue);
+        let (service, shutdown_sender, consumer_thread) = start(
+            Box::new(transport),
+            job_queue,
+            running_status,
+            Scale::default(),
+            limits,
+            PaperSize::default(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::task::spawn(
+            Server::builder()
+                .add_service(AxidrawOverHttpServer::from_arc(service.clone()))
+                .add_service(AxidrawOverTcpServer::from_arc(service.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        Harness {
+            service,
+            client: AxidrawOverHttpClient::new(channel.clone()),
+            extensions: AxidrawOverTcpClient::new(channel),
+            written,
+            interference,
+            rejection,
+            unplug,
+            shutdown_sender,
+            consumer_thread: Some(consumer_thread),
+        }
+    }
+
+    async fn stream(&mut self, commands: &[&str]) -> Result<(), tonic::Status> {
+        let commands = commands
+            .iter()
+            .map(|contents| Command {
+                contents: contents.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        self.client
+            .stream(tokio_stream::iter(commands))
+            .await
+            .map(|_| ())
+    }
+
+    async fn pause(&mut self) {
+        self.client.pause(Empty {}).await.unwrap();
+    }
+
+    async fn resume(&mut self) {
+        self.client.resume(Empty {}).await.unwrap();
+    }
+
+    async fn clear(&mut self) {
+        self.client.clear(Empty {}).await.unwrap();
+    }
+
+    async fn state(&mut self) -> BufferState {
+        self.client.get_state(Empty {}).await.unwrap().into_inner()
+    }
+
+    async fn status(&mut self) -> PlotterState {
+        self.extensions
+            .get_status(Empty {})
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    /// Waits for the tracked step position to be `motor1`,`motor2`.
+    async fn assert_step_position(&mut self, motor1: i32, motor2: i32) {
+        let expected = Some(StepPosition { motor1, motor2 });
+        let deadline = Instant::now() + TIMEOUT;
+        while self.status().await.position != expected && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(self.status().await.position, expected);
+    }
+
+    async fn jobs(&mut self) -> Vec<JobInfo> {
+        let jobs = self.extensions.list_jobs(Empty {}).await.unwrap();
+
+        jobs.into_inner().jobs
+    }
+
+    async fn job_names(&mut self) -> Vec<String> {
+        self.jobs().await.into_iter().map(|job| job.name).collect()
+    }
+
+    async fn dry_run(&mut self, commands: &[&str]) -> DryRunReport {
+        let request = JobRequest {
+            name: String::new(),
+            commands: commands.iter().map(|command| command.to_string()).collect(),
+        };
+
+        self.extensions.dry_run(request).await.unwrap().into_inner()
+    }
+
+    /// Draws what `commands` would plot from home.
+    async fn preview(&mut self, commands: &[&str], format: ImageFormat) -> PreviewReply {
+        let request = PreviewRequest {
+            commands: commands.iter().map(|command| command.to_string()).collect(),
+            format: format as i32,
+        };
+
+        self.extensions.preview(request).await.unwrap().into_inner()
+    }
+
+    /// Shuts the service down as a signal would, and waits for the consumer thread to finish.
+    async fn shut_down(&mut self, drain: bool) {
+        begin_shutdown(&self.service, &self.shutdown_sender, drain).await;
+
+        let consumer_thread = self.consumer_thread.take().unwrap();
+        tokio::time::timeout(
+            TIMEOUT,
+            tokio::task::spawn_blocking(move || consumer_thread.join()),
+        )
+        .await
+        .expect("the consumer thread should stop")
+        .unwrap()
+        .unwrap();
+    }
+
+    /// Sends `command` to the EBB just before the service's next command, behind its back.
+    fn interfere(&self, command: &str) {
+        *self.interference.lock().unwrap() = Some(command.to_string());
+    }
+
+    /// Has the EBB answer the next command with the firmware error `error`.
+    fn reject(&self, error: &str) {
+        *self.rejection.lock().unwrap() = Some(error.to_string());
+    }
+
+    /// Unplugs the EBB at the next command, and plugs a fresh one back in.
+    fn unplug(&self, when: Unplug) {
+        *self.unplug.lock().unwrap() = Some(when);
+    }
+
+    fn written(&self) -> String {
+        String::from_utf8(self.written.lock().unwrap().clone()).unwrap()
+    }
+
+    /// Waits for the consumer thread to have written exactly `expected`.
+    async fn assert_written(&self, expected: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.written() != expected && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(self.written(), expected);
+    }
+
+    /// Gives the consumer thread the chance to write anything it should not.
+    async fn assert_nothing_more_written(&self, expected: &str) {
+        sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(self.written(), expected);
+    }
+
+    /// Waits for the queue to be empty.
+    async fn wait_until_idle(&mut self) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.state().await.buffer_length > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// A port nothing is listening on, for the servers that bind their own.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("[::]:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Connects to a server spawned on `port`, once it is listening.
+async fn connect(port: u16) -> TcpStream {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match TcpStream::connect(("::1", port)).await {
+            Ok(stream) => return stream,
+            Err(e) if Instant::now() >= deadline => panic!("Could not connect: {}", e),
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Makes a request of the HTTP API on `port`, returning the status code and the JSON reply, or
+/// null if there is none.
+async fn http_request(port: u16, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    let body = body.map_or_else(String::new, |body| body.to_string());
+    let mut stream = connect(port).await;
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(body).unwrap()
+    };
+
+    (status, body)
+}
+
+/// Reads messages from the WebSocket until one satisfies `matches`, returning it.
+async fn next_message_where(
+    socket: &mut WebSocketStream<TcpStream>,
+    matches: impl Fn(&Value) -> bool,
+) -> Value {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let message = tokio::time::timeout_at(deadline, socket.next())
+            .await
+            .expect("no matching message arrived")
+            .unwrap()
+            .unwrap();
+        let message = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        if matches(&message) {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn streamed_commands_are_written_in_order() {
+    let mut harness = Harness::start().await;
+
+    harness
+        .stream(&["SM,10,100,100", "SP,0", "XM,10,-50,-50", "SP,1"])
+        .await
+        .unwrap();
+
+    harness
+        .assert_written("V\rSM,10,100,100\rSP,0\rXM,10,-50,-50\rSP,1\r")
+        .await;
+    harness.wait_until_idle().await;
+    assert_eq!(
+        harness.state().await,
+        BufferState {
+            buffer_length: 0,
+            running_status: RunningStatus::Running as i32,
+        }
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn status_can_be_polled_while_the_queue_runs() {
+    let harness = Harness::start().await;
+    let commands = vec![
+        Command {
+            contents: "SL,1".to_string(),
+        };
+        10000
+    ];
+    let expected = format!("{}{}", HANDSHAKE, "SL,1\r".repeat(commands.len()));
+
+    let mut client = harness.client.clone();
+    let stream = tokio::task::spawn(async move {
+        client.stream(tokio_stream::iter(commands)).await.unwrap();
+    });
+
+    // Each poll competes with the consumer thread for the status and the queue.
+    let pollers = (0..8)
+        .map(|_| {
+            let service = harness.service.clone();
+            let written = harness.written.clone();
+            let expected = expected.clone();
+            tokio::task::spawn(async move {
+                let deadline = Instant::now() + TIMEOUT;
+                while *written.lock().unwrap() != expected.as_bytes() && Instant::now() < deadline {
+                    service.plotter_state().await;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for poller in pollers {
+        tokio::time::timeout(TIMEOUT * 2, poller)
+            .await
+            .expect("polling the status should not deadlock the queue")
+            .unwrap();
+    }
+
+    stream.await.unwrap();
+    assert_eq!(harness.written(), expected);
+}
+
+#[tokio::test]
+async fn commands_are_written_in_canonical_form() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["sm,10,5", "sp,0,"]).await.unwrap();
+
+    harness.assert_written("V\rSM,10,5\rSP,0\r").await;
+}
+
+#[tokio::test]
+async fn invalid_command_ends_the_stream_after_earlier_commands_run() {
+    let mut harness = Harness::start().await;
+
+    let error = harness
+        .stream(&["SM,10,1,1", "XX,1", "SM,10,2,2"])
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.code(), Code::InvalidArgument);
+    harness.assert_written("V\rSM,10,1,1\r").await;
+    harness.assert_nothing_more_written("V\rSM,10,1,1\r").await;
+}
+
+#[tokio::test]
+async fn firmware_error_pauses_the_queue_until_resumed() {
+    let mut harness = Harness::start().await;
+
+    harness.reject("!8 Err: Unknown command");
+    harness.stream(&["SM,10,1,1", "SM,10,2,2"]).await.unwrap();
+
+    harness.assert_nothing_more_written("V\rSM,10,1,1\r").await;
+    let status = harness.status().await;
+    let error = status.last_error.unwrap();
+    assert_eq!(error.command, "SM,10,1,1");
+    assert_eq!(error.error, "!8 Err: Unknown command");
+    assert_eq!(
+        status.buffer_state.unwrap(),
+        BufferState {
+            buffer_length: 1,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+
+    // The rejected command is not retried.
+    harness.resume().await;
+    harness.assert_written("V\rSM,10,1,1\rSM,10,2,2\r").await;
+}
+
+#[tokio::test]
+async fn pause_holds_commands_until_resumed() {
+    let mut harness = Harness::start().await;
+
+    harness.pause().await;
+    harness.stream(&["SM,10,1,1", "SM,10,2,2"]).await.unwrap();
+
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+    assert_eq!(
+        harness.state().await,
+        BufferState {
+            buffer_length: 2,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+
+    harness.resume().await;
+
+    harness.assert_written("V\rSM,10,1,1\rSM,10,2,2\r").await;
+    harness.wait_until_idle().await;
+    assert_eq!(
+        harness.state().await.running_status,
+        RunningStatus::Running as i32
+    );
+}
+
+#[tokio::test]
+async fn query_runs_ahead_of_the_queued_commands() {
+    let mut harness = Harness::start().await;
+
+    harness.pause().await;
+    harness.stream(&["SM,10,100,0"]).await.unwrap();
+
+    let reply = harness
+        .extensions
+        .query(Command {
+            contents: "QS".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(
+        reply.reply,
+        Some(Reply::StepPosition(StepPosition {
+            motor1: 0,
+            motor2: 0,
+        }))
+    );
+    harness.assert_written("V\rQS\r").await;
+    assert_eq!(harness.state().await.buffer_length, 1);
+
+    let error = harness
+        .extensions
+        .query(Command {
+            contents: "SM,10,1,1".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Code::InvalidArgument);
+
+    harness.resume().await;
+    harness.assert_written("V\rQS\rSM,10,100,0\r").await;
+}
+
+#[tokio::test]
+async fn pause_raises_the_pen_and_resume_lowers_it() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SP,0"]).await.unwrap();
+    harness.assert_written("V\rSP,0\r").await;
+
+    harness.pause().await;
+    harness.assert_written("V\rSP,0\rSP,1\r").await;
+
+    harness.stream(&["SM,10,3,4"]).await.unwrap();
+    harness.assert_nothing_more_written("V\rSP,0\rSP,1\r").await;
+
+    harness.resume().await;
+    harness
+        .assert_written("V\rSP,0\rSP,1\rSP,0\rSM,10,3,4\r")
+        .await;
+}
+
+#[tokio::test]
+async fn clear_discards_buffered_commands() {
+    let mut harness = Harness::start().await;
+
+    harness.pause().await;
+    harness.stream(&["SM,10,1,1", "SM,10,2,2"]).await.unwrap();
+    assert_eq!(harness.state().await.buffer_length, 2);
+
+    harness.clear().await;
+    assert_eq!(harness.state().await.buffer_length, 0);
+
+    harness.resume().await;
+    harness.stream(&["SM,10,3,3"]).await.unwrap();
+
+    harness.assert_written("V\rSM,10,3,3\r").await;
+}
+
+#[tokio::test]
+async fn tcp_connection_queues_its_lines_as_one_job() {
+    let mut harness = Harness::start().await;
+    let port = free_port();
+    tokio::task::spawn(tcp::serve(harness.service.clone(), port));
+
+    harness.pause().await;
+    let stream = connect(port).await;
+    let address = stream.local_addr().unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut replies = tokio::io::BufReader::new(reader).lines();
+    writer
+        .write_all(b"SM,10,1,1\r\nXX,1\r\n\r\nsm,10,2,2\n")
+        .await
+        .unwrap();
+
+    assert_eq!(replies.next_line().await.unwrap().unwrap(), "OK");
+    assert_eq!(
+        replies.next_line().await.unwrap().unwrap(),
+        "!Invalid command 'XX,1': unknown command 'XX'"
+    );
+    assert_eq!(replies.next_line().await.unwrap().unwrap(), "OK");
+
+    let jobs = harness.jobs().await;
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].name, format!("tcp-{}", address));
+    assert_eq!(jobs[0].total_commands, 2);
+    assert!(jobs[0].open);
+
+    // Hanging up closes the job, so the queue can move on past it.
+    drop(writer);
+    let deadline = Instant::now() + TIMEOUT;
+    while harness.jobs().await[0].open && Instant::now() < deadline {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!harness.jobs().await[0].open);
+
+    harness.resume().await;
+    harness.assert_written("V\rSM,10,1,1\rSM,10,2,2\r").await;
+}
+
+#[tokio::test]
+async fn http_api_queues_named_jobs_and_reports_state() {
+    let harness = Harness::start().await;
+    let port = free_port();
+    tokio::task::spawn(http::serve(harness.service.clone(), port));
+
+    assert_eq!(http_request(port, "POST", "/pause", None).await.0, 204);
+
+    // A bad line refuses the whole batch.
+    let (status, reply) = http_request(
+        port,
+        "POST",
+        "/commands",
+        Some(json!({"commands": ["SM,10,1,1", "XX,1"]})),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(
+        reply,
+        json!({"error": "Invalid command 'XX,1': unknown command 'XX'"})
+    );
+
+    let (status, reply) = http_request(
+        port,
+        "POST",
+        "/commands",
+        Some(json!({"name": "drawing", "commands": ["SM,10,1,1", "sm,10,2,2"]})),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(reply["name"], "drawing");
+    assert_eq!(reply["total_commands"], 2);
+
+    let (status, state) = http_request(port, "GET", "/state", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(state["buffer_length"], 2);
+    assert_eq!(state["running_status"], "PAUSED");
+    assert_eq!(state["current_job"]["name"], "drawing");
+
+    assert_eq!(http_request(port, "POST", "/resume", None).await.0, 204);
+    harness.assert_written("V\rSM,10,1,1\rSM,10,2,2\r").await;
+}
+
+#[tokio::test]
+async fn websocket_controls_the_queue_and_pushes_state_changes() {
+    let harness = Harness::start().await;
+    let port = free_port();
+    tokio::task::spawn(http::serve(harness.service.clone(), port));
+
+    let url = format!("ws://[::1]:{}/ws", port);
+    let (mut socket, _) = client_async(url, connect(port).await).await.unwrap();
+    next_message_where(&mut socket, |message| message["type"] == "state").await;
+
+    socket
+        .send(Message::text(json!({"type": "pause"}).to_string()))
+        .await
+        .unwrap();
+    next_message_where(&mut socket, |message| message["running_status"] == "PAUSED").await;
+
+    socket
+        .send(Message::text(
+            json!({"type": "commands", "commands": ["XX,1"]}).to_string(),
+        ))
+        .await
+        .unwrap();
+    let error = next_message_where(&mut socket, |message| message["type"] == "error").await;
+    assert_eq!(
+        error["error"],
+        "Invalid command 'XX,1': unknown command 'XX'"
+    );
+
+    socket
+        .send(Message::text(
+            json!({"type": "commands", "commands": ["SM,10,1,1", "SM,10,2,2"]}).to_string(),
+        ))
+        .await
+        .unwrap();
+    next_message_where(&mut socket, |message| message["buffer_length"] == 2).await;
+
+    socket
+        .send(Message::text(json!({"type": "resume"}).to_string()))
+        .await
+        .unwrap();
+    harness.assert_written("V\rSM,10,1,1\rSM,10,2,2\r").await;
+    next_message_where(&mut socket, |message| {
+        message["buffer_length"] == 0 && message["last_command"] == "SM,10,2,2"
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn shutdown_keeps_the_queue_and_parks_the_plotter() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SP,0", "SM,10,1,1"]).await.unwrap();
+    harness.wait_until_idle().await;
+    harness.pause().await;
+    harness.stream(&["SM,10,2,2"]).await.unwrap();
+
+    harness.shut_down(false).await;
+
+    harness
+        .assert_written("V\rSP,0\rSM,10,1,1\rSP,1\rSP,1\rEM,0,0\r")
+        .await;
+    assert_eq!(harness.state().await.buffer_length, 1);
+    let error = harness.stream(&["SM,10,3,3"]).await.unwrap_err();
+    assert_eq!(error.code(), Code::Unavailable);
+}
+
+#[tokio::test]
+async fn draining_shutdown_finishes_the_queue_first() {
+    let mut harness = Harness::start().await;
+
+    harness
+        .stream(&["SP,0", "SM,100,10,0", "SM,100,0,10"])
+        .await
+        .unwrap();
+    harness.shut_down(true).await;
+
+    harness
+        .assert_written("V\rSP,0\rSM,100,10,0\rSM,100,0,10\rSP,1\rEM,0,0\r")
+        .await;
+    assert_eq!(harness.state().await.buffer_length, 0);
+}
+
+#[tokio::test]
+async fn recover_returns_to_the_acknowledged_position_and_carries_on() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SP,0", "SM,100,80,0"]).await.unwrap();
+    harness.wait_until_idle().await;
+    harness.pause().await;
+    harness.stream(&["SM,100,0,80"]).await.unwrap();
+    let before = "V\rSP,0\rSM,100,80,0\rSP,1\r";
+    harness.assert_written(before).await;
+
+    // The carriage is knocked out of place behind the service's back.
+    harness.interfere("SM,10,10,10");
+    harness.extensions.recover(Empty {}).await.unwrap();
+
+    harness
+        .assert_written(&format!(
+            "{}SP,1\rHM,2000\rHM,2000,80,0\rSP,0\rSM,100,0,80\r",
+            before
+        ))
+        .await;
+    harness.wait_until_idle().await;
+    assert_eq!(
+        harness.state().await.running_status,
+        RunningStatus::Running as i32
+    );
+
+    // Let the last move finish before asking where the motors are.
+    sleep(Duration::from_millis(300)).await;
+    let reply = harness
+        .extensions
+        .query(Command {
+            contents: "QS".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        reply.reply,
+        Some(Reply::StepPosition(StepPosition {
+            motor1: 80,
+            motor2: 80,
+        }))
+    );
+}
+
+#[tokio::test]
+async fn emergency_stop_cuts_the_move_short_and_holds_back_the_queue() {
+    let mut harness = Harness::start().await;
+
+    // Each move takes a second: one runs, one waits in the FIFO and the EBB holds back the
+    // third's OK until there is room for it.
+    let long_move = "SM,1000,10000,0";
+    harness
+        .stream(&[long_move, long_move, long_move, "SM,10,1,1"])
+        .await
+        .unwrap();
+    let moves = format!("{}{}", HANDSHAKE, format!("{}\r", long_move).repeat(3));
+    harness.assert_written(&moves).await;
+    sleep(Duration::from_millis(100)).await;
+
+    let reply = harness
+        .extensions
+        .emergency_stop(EmergencyStopRequest { clear_queue: false })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(reply.interrupted);
+    assert_eq!((reply.fifo_steps1, reply.fifo_steps2), (10000, 0));
+    // The motors stopped partway through the first move.
+    let position = reply.position.unwrap();
+    assert!(0 < position.motor1 && position.motor1 < 10000);
+    harness.assert_step_position(position.motor1, 0).await;
+
+    let stopped = format!("{}ES\rSP,1\rQS\r", moves);
+    harness.assert_nothing_more_written(&stopped).await;
+    assert_eq!(
+        harness.state().await,
+        BufferState {
+            buffer_length: 1,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+
+    harness.resume().await;
+    harness
+        .assert_written(&format!("{}SM,10,1,1\r", stopped))
+        .await;
+}
+
+#[tokio::test]
+async fn jobs_run_one_after_another_without_interleaving() {
+    let mut harness = Harness::start().await;
+
+    // The first stream stays open, holding the queue until it is done.
+    let (sender, receiver) = mpsc::channel(1);
+    let mut client = harness.client.clone();
+    let first =
+        tokio::task::spawn(async move { client.stream(ReceiverStream::new(receiver)).await });
+    let command = |contents: &str| Command {
+        contents: contents.to_string(),
+    };
+    sender.send(command("SM,10,1,1")).await.unwrap();
+    harness.assert_written("V\rSM,10,1,1\r").await;
+
+    harness.stream(&["SM,10,2,2"]).await.unwrap();
+    let jobs = harness.jobs().await;
+    assert_eq!(
+        jobs.iter()
+            .map(|job| (job.id, job.total_commands, job.open))
+            .collect::<Vec<_>>(),
+        [(1, 1, true), (2, 1, false)]
+    );
+    harness.assert_nothing_more_written("V\rSM,10,1,1\r").await;
+
+    sender.send(command("SM,10,3,3")).await.unwrap();
+    drop(sender);
+    first.await.unwrap().unwrap();
+
+    harness
+        .assert_written("V\rSM,10,1,1\rSM,10,3,3\rSM,10,2,2\r")
+        .await;
+    harness.wait_until_idle().await;
+    assert!(harness.jobs().await.is_empty());
+}
+
+#[tokio::test]
+async fn job_moved_ahead_of_an_idle_stream_runs_at_once() {
+    let mut harness = Harness::start().await;
+
+    // The stream opens a job, but sends nothing yet.
+    let (sender, receiver) = mpsc::channel(1);
+    let mut client = harness.client.clone();
+    let idle =
+        tokio::task::spawn(async move { client.stream(ReceiverStream::new(receiver)).await });
+    let deadline = Instant::now() + TIMEOUT;
+    while harness.jobs().await.is_empty() && Instant::now() < deadline {
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    harness.stream(&["SM,10,1,1"]).await.unwrap();
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+
+    harness
+        .extensions
+        .move_job(MoveJobRequest { id: 2, offset: -1 })
+        .await
+        .unwrap();
+    harness.assert_written("V\rSM,10,1,1\r").await;
+
+    drop(sender);
+    idle.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn jobs_moved_by_extreme_offsets_go_to_the_ends_of_the_queue() {
+    let mut harness = Harness::start().await;
+
+    harness.pause().await;
+    for name in ["first", "second", "third"] {
+        harness
+            .extensions
+            .submit_job(JobRequest {
+                name: name.to_string(),
+                commands: vec!["SM,10,1,1".to_string()],
+            })
+            .await
+            .unwrap();
+    }
+
+    // The jobs are numbered from 1 in the order they were submitted.
+    harness
+        .extensions
+        .move_job(MoveJobRequest {
+            id: 2,
+            offset: i64::MAX,
+        })
+        .await
+        .unwrap();
+    assert_eq!(harness.job_names().await, ["first", "third", "second"]);
+
+    harness
+        .extensions
+        .move_job(MoveJobRequest {
+            id: 3,
+            offset: i64::MIN,
+        })
+        .await
+        .unwrap();
+    assert_eq!(harness.job_names().await, ["third", "first", "second"]);
+}
+
+#[tokio::test]
+async fn dry_run_times_and_measures_a_job_without_running_it() {
+    let mut harness = Harness::start().await;
+
+    let report = harness
+        .dry_run(&[
+            "SP,0,100",
+            // 10 mm along X, then 10 mm along Y.
+            "SM,400,800,800",
+            "SM,300,800,-800",
+            "SP,1,100",
+            // 1600 steps on motor 1 at 8000 steps per second.
+            "HM,8000",
+        ])
+        .await;
+
+    assert_eq!(
+        report,
+        DryRunReport {
+            duration_ms: 100 + 400 + 300 + 100 + 200,
+            pen_down_distance_steps: 1600.0,
+            pen_down_distance_mm: 20.0,
+            bounds_steps: Some(Bounds {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 800.0,
+                max_y: 800.0,
+            }),
+            bounds_mm: Some(Bounds {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 10.0,
+                max_y: 10.0,
+            }),
+            pen_lifts: 1,
+            invalid_commands: vec![],
+        }
+    );
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+}
+
+#[tokio::test]
+async fn dry_run_lists_and_skips_invalid_commands() {
+    let mut harness = Harness::with_limits(Some(Model::V3.travel_limits())).await;
+
+    let report = harness
+        .dry_run(&[
+            "SM,100,800,800",
+            "XX,1",
+            "SM,100,-1600,-1600",
+            "SM,100,-800,-800",
+        ])
+        .await;
+
+    assert_eq!(report.duration_ms, 200);
+    assert_eq!(
+        report.bounds_mm,
+        Some(Bounds {
+            min_x: 0.0,
+            min_y: 0.0,
+            max_x: 10.0,
+            max_y: 0.0,
+        })
+    );
+    assert_eq!(
+        report.invalid_commands,
+        [
+            InvalidCommand {
+                index: 1,
+                command: "XX,1".to_string(),
+                error: "unknown command 'XX'".to_string(),
+            },
+            InvalidCommand {
+                index: 2,
+                command: "SM,100,-1600,-1600".to_string(),
+                error: "Move through -10.0,0.0 mm would leave the 300x218 mm travel limits"
+                    .to_string(),
+            },
+        ]
+    );
+}
+
+/// Draws a 10 mm line along X, then one along Y.
+const L_SHAPE: [&str; 3] = ["SP,0", "SM,100,800,800", "SM,100,800,-800"];
+
+#[tokio::test]
+async fn svg_preview_draws_the_pen_down_moves_in_millimetres() {
+    let mut harness = Harness::start().await;
+
+    let preview = harness.preview(&L_SHAPE, ImageFormat::Svg).await;
+
+    assert_eq!(preview.content_type, "image/svg+xml");
+    assert_eq!(
+        String::from_utf8(preview.image).unwrap(),
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="11mm" height="11mm" "#,
+            r#"viewBox="-0.5 -0.5 11 11"><path d="M0 0L10 0L10 10" fill="none" stroke="black" "#,
+            r#"stroke-width="0.5" stroke-linecap="round" stroke-linejoin="round"/></svg>"#,
+        )
+    );
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+}
+
+#[tokio::test]
+async fn png_preview_decodes_to_the_drawing() {
+    let mut harness = Harness::start().await;
+
+    let preview = harness.preview(&L_SHAPE, ImageFormat::Png).await;
+
+    assert_eq!(preview.content_type, "image/png");
+    let mut decoder = png::Decoder::new(preview.image.as_slice());
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().unwrap();
+    let info = reader.info();
+    assert_eq!(
+        (info.color_type, info.bit_depth),
+        (png::ColorType::Grayscale, png::BitDepth::One)
+    );
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((frame.width, frame.height), (1024, 1024));
+
+    // The drawing is offset by the pen's width, which scales to about 47 pixels.
+    let pixel = |x: usize, y: usize| pixels[y * frame.line_size + x];
+    assert_eq!(pixel(512, 46), 0, "along the line on X");
+    assert_eq!(pixel(977, 512), 0, "along the line on Y");
+    assert_eq!(pixel(512, 512), 255, "away from the lines");
+    assert_eq!(pixel(46, 977), 255, "where the pen was never down");
+}
+
+#[tokio::test]
+async fn move_beyond_travel_limits_is_refused_and_pauses_the_queue() {
+    let mut harness = Harness::with_limits(Some(Model::V3.travel_limits())).await;
+
+    harness
+        .stream(&["SM,100,800,0", "SM,100,-1600,0", "SM,100,0,800"])
+        .await
+        .unwrap();
+
+    harness
+        .assert_nothing_more_written("V\rSM,100,800,0\r")
+        .await;
+    let refused = BufferState {
+        buffer_length: 2,
+        running_status: RunningStatus::Paused as i32,
+    };
+    assert_eq!(harness.state().await, refused);
+
+    // The refused move stays at the head of the queue.
+    harness.resume().await;
+    harness
+        .assert_nothing_more_written("V\rSM,100,800,0\r")
+        .await;
+    assert_eq!(harness.state().await, refused);
+
+    // Clearing a started job raises the pen in case it was left down.
+    harness.clear().await;
+    harness.resume().await;
+    harness.stream(&["SM,100,0,800"]).await.unwrap();
+    harness
+        .assert_written("V\rSM,100,800,0\rSP,1\rSM,100,0,800\r")
+        .await;
+}
+
+#[tokio::test]
+async fn low_level_moves_are_refused_if_they_could_curve_beyond_the_travel_limits() {
+    let mut harness = Harness::with_limits(Some(Model::V3.travel_limits())).await;
+
+    // Ends 10 mm along X, but if motor 2 ran first it would pass 5 mm behind home.
+    harness
+        .stream(&["LM,85899346,800,0,85899346,-800,0", "SM,100,800,800"])
+        .await
+        .unwrap();
+
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+    assert_eq!(
+        harness.status().await.last_error.unwrap().error,
+        "Move through -5.0,5.0 mm would leave the 300x218 mm travel limits"
+    );
+}
+
+#[tokio::test]
+async fn progress_counts_what_has_run_and_estimates_what_is_left() {
+    let mut harness = Harness::start().await;
+
+    // The rejected command pauses the queue part way through the job, counting as run.
+    harness.reject("!8 Err: Unknown command");
+    harness
+        .stream(&["SM,100,800,0", "SM,200,0,800", "SP,0,50"])
+        .await
+        .unwrap();
+    harness
+        .assert_nothing_more_written("V\rSM,100,800,0\r")
+        .await;
+
+    let progress = harness.status().await.progress.unwrap();
+    assert_eq!(progress.executed_commands, 1);
+    assert_eq!(progress.remaining_ms, 200 + 50);
+    assert!(
+        progress.percent_complete > 0.0 && progress.percent_complete < 100.0,
+        "{}",
+        progress.percent_complete
+    );
+
+    harness.clear().await;
+
+    let progress = harness.status().await.progress.unwrap();
+    assert_eq!(progress.remaining_ms, 0);
+}
+
+#[tokio::test]
+async fn enabling_the_motors_zeroes_the_tracked_position() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SM,100,800,0"]).await.unwrap();
+    harness.assert_step_position(800, 0).await;
+
+    harness.stream(&["EM,2,1"]).await.unwrap();
+    harness.assert_written("V\rSM,100,800,0\rEM,2,1\r").await;
+    harness.assert_step_position(0, 0).await;
+    assert_eq!(harness.status().await.carriage.unwrap().microstepping, 8);
+}
+
+#[tokio::test]
+async fn reconcile_corrects_a_drifted_position_from_the_step_counters() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["EM,1,1", "SM,100,800,0"]).await.unwrap();
+    harness.assert_step_position(800, 0).await;
+    // Reconciling waits for the motors to be idle.
+    sleep(Duration::from_millis(200)).await;
+
+    // Clearing the counters behind the service's back leaves them behind the tracked position.
+    harness.interfere("CS");
+    harness
+        .service
+        .control_message_sender
+        .send(ControlMessage::Reconcile)
+        .unwrap();
+
+    harness
+        .assert_written("V\rEM,1,1\rSM,100,800,0\rQM\rQS\r")
+        .await;
+    harness.assert_step_position(0, 0).await;
+}
+
+#[tokio::test]
+async fn journalled_queue_is_restored_paused() {
+    let path = std::env::temp_dir().join(format!("axidraw-restore-{}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut harness = Harness::with(JobQueue::restore(&path).unwrap(), None).await;
+    harness.stream(&["SM,10,1,1"]).await.unwrap();
+    harness.wait_until_idle().await;
+    harness.pause().await;
+    harness.stream(&["SM,10,2,2", "SM,10,3,3"]).await.unwrap();
+
+    // As if the service restarted, with the finished job gone from the queue.
+    let mut harness = Harness::with(JobQueue::restore(&path).unwrap(), None).await;
+
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+    assert_eq!(
+        harness.state().await,
+        BufferState {
+            buffer_length: 2,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+    assert_eq!(harness.jobs().await[0].id, 2);
+    harness.assert_step_position(1, 1).await;
+
+    harness.resume().await;
+    harness.assert_written("V\rSM,10,2,2\rSM,10,3,3\r").await;
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn reconcile_keeps_a_restored_position_until_the_plotter_is_homed() {
+    // The journal has the plotter away from home, but the EBB's counters start at zero.
+    let path = std::env::temp_dir().join(format!("axidraw-reconcile-{}.jsonl", std::process::id()));
+    let position = Position {
+        motor1: 800,
+        motor2: 800,
+        ..Position::default()
+    };
+    Journal::create(&path, &[JournalEntry::Position { position }]).unwrap();
+    let job_queue = JobQueue::restore(&path).unwrap();
+    let mut harness = Harness::with(job_queue, None).await;
+    harness.assert_step_position(800, 800).await;
+
+    harness
+        .service
+        .control_message_sender
+        .send(ControlMessage::Reconcile)
+        .unwrap();
+
+    harness.assert_nothing_more_written(HANDSHAKE).await;
+    harness.assert_step_position(800, 800).await;
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn return_home_runs_while_paused_and_goes_back_to_the_origin_pen_up() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SM,100,800,0"]).await.unwrap();
+    harness.assert_written("V\rSM,100,800,0\r").await;
+    harness
+        .extensions
+        .set_origin(SetOriginRequest { reset: false })
+        .await
+        .unwrap();
+
+    harness.stream(&["SP,0", "SM,100,0,800"]).await.unwrap();
+    harness
+        .assert_written("V\rSM,100,800,0\rSP,0\rSM,100,0,800\r")
+        .await;
+
+    harness.pause().await;
+    harness
+        .assert_written("V\rSM,100,800,0\rSP,0\rSM,100,0,800\rSP,1\r")
+        .await;
+    harness.stream(&["SM,100,10,10"]).await.unwrap();
+    let distance = harness
+        .extensions
+        .return_home(ReturnHomeRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+
+    let homed = "V\rSM,100,800,0\rSP,0\rSM,100,0,800\rSP,1\rSP,1\rHM,2000,800,0\r";
+    harness.assert_nothing_more_written(homed).await;
+    assert_eq!(distance.distance_from_origin_mm, 0.0);
+    assert_eq!(distance.distance_from_home_mm, 50.0_f64.sqrt());
+
+    // The pen stays up, since the job's drawing no longer continues from where it was lowered.
+    harness.resume().await;
+    harness
+        .assert_written(&format!("{}SM,100,10,10\r", homed))
+        .await;
+}
+
+#[tokio::test]
+async fn svg_is_scaled_to_the_paper_and_drawn_between_returns_home() {
+    let mut harness = Harness::start().await;
+
+    // A tenth of the way down a viewBox scaled ten times to fit the A4 paper.
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 29.7 21">
+        <line x1="0" y1="0" x2="0" y2="0.1" stroke="black"/>
+        <text x="5" y="5">Not drawn</text>
+    </svg>"#;
+    let job = harness
+        .extensions
+        .submit_svg(SvgRequest {
+            name: "line".to_string(),
+            svg: svg.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(job.name, "line");
+
+    harness
+        .assert_written("V\rSP,1,150\rHM,6000\rSP,0,150\rSM,40,80,-80\rSP,1,150\rHM,6000\r")
+        .await;
+
+    let error = harness
+        .extensions
+        .submit_svg(SvgRequest {
+            svg: "<svg viewBox=\"0 0 10 10\"/>".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn oversized_svg_curves_are_drawn_with_a_bounded_number_of_lines() {
+    let mut harness = Harness::start().await;
+    harness.pause().await;
+
+    // Starts on the paper, with control points far beyond it.
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">
+        <path d="M 10 10 C 1e150 10 10 1e150 20 20"/>
+    </svg>"#;
+    let job = harness
+        .extensions
+        .submit_svg(SvgRequest {
+            svg: svg.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(
+        job.total_commands <= 1010,
+        "{} commands",
+        job.total_commands
+    );
+
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">
+        <path d="M 10 10 L 1e308 10" transform="scale(10)"/>
+    </svg>"#;
+    let error = harness
+        .extensions
+        .submit_svg(SvgRequest {
+            svg: svg.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn disconnect_pauses_the_queue_until_resumed_after_reconnecting() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SM,10,100,0"]).await.unwrap();
+    harness.assert_step_position(100, 0).await;
+
+    harness.unplug(Unplug::BeforeWrite);
+    harness.stream(&["SM,10,0,100"]).await.unwrap();
+
+    // The failed command never reached the EBB, so it stays queued.
+    let reconnected = "V\rSM,10,100,0\rV\r";
+    harness.assert_written(reconnected).await;
+    let status = harness.status().await;
+    let error = status.last_error.unwrap();
+    assert_eq!(error.command, "SM,10,0,100");
+    assert!(
+        error.error.starts_with("serial port disconnected: "),
+        "{}",
+        error.error
+    );
+    assert_eq!(
+        status.buffer_state.unwrap(),
+        BufferState {
+            buffer_length: 1,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+    harness.assert_nothing_more_written(reconnected).await;
+
+    harness.resume().await;
+    harness
+        .assert_written(&format!("{}SM,10,0,100\r", reconnected))
+        .await;
+    harness.assert_step_position(100, 100).await;
+}
+
+#[tokio::test]
+async fn a_command_left_unanswered_by_a_disconnect_has_to_be_recovered() {
+    let mut harness = Harness::start().await;
+
+    harness.stream(&["SM,10,100,0"]).await.unwrap();
+    harness.assert_step_position(100, 0).await;
+
+    harness.unplug(Unplug::BeforeReply);
+    harness.stream(&["SM,10,0,100"]).await.unwrap();
+
+    // It may or may not have run, so it is kept but not sent again.
+    let reconnected = "V\rSM,10,100,0\rSM,10,0,100\rV\r";
+    harness.assert_written(reconnected).await;
+    assert_eq!(
+        harness.state().await,
+        BufferState {
+            buffer_length: 1,
+            running_status: RunningStatus::Paused as i32,
+        }
+    );
+    assert_eq!(
+        harness.client.resume(Empty {}).await.unwrap_err().code(),
+        Code::FailedPrecondition
+    );
+    harness.assert_nothing_more_written(reconnected).await;
+
+    harness.extensions.recover(Empty {}).await.unwrap();
+    harness
+        .assert_written(&format!(
+            "{}SP,1\rHM,2000\rHM,2000,100,0\rSM,10,0,100\r",
+            reconnected
+        ))
+        .await;
+    harness.assert_step_position(100, 100).await;
+}